@@ -0,0 +1,501 @@
+// The syntax tree of a single run file line, before any variables are
+// resolved.
+//
+//  & - cat src/main.rs | rg "fn main" > out.txt 2>&1 ; make && echo done
+//  ^ ^ ^---------------^ ^------------^ ^-------^ ^--^   ^--^    ^-------^
+//  | |     Command           Command    terminus stderr Command   Command
+//  | ignore failure
+//  parallel
+//  ^-------------------- Pipeline ------------------^   ^--^ Op ^-------^
+//                                                        ^---- Chain ----^
+//
+// Every pipeline belongs to a chain, most of which are a single pipeline.
+
+use crate::error::{Error, Span};
+use crate::lexer::{lex, Part, Token, Word};
+use std::fmt;
+
+#[derive(Debug, PartialEq)]
+pub struct Line {
+    // Line runs concurrently with its neighbours, "& ".
+    pub parallel: bool,
+    pub chains: Vec<Chain>,
+}
+
+// Chain of pipelines, each of which runs depending on how the previously run
+// one went, like a shell's "&&" and "||" lists.
+#[derive(Debug, PartialEq)]
+pub struct Chain {
+    pub first: Pipeline,
+    pub rest: Vec<(Op, Pipeline)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    // Run if the previous pipeline succeeded, "&&".
+    And,
+    // Run if the previous pipeline failed, "||".
+    Or,
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Op::And => write!(f, "&&"),
+            Op::Or => write!(f, "||"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Pipeline {
+    // Failure is reported but doesn't stop the run, "- ".
+    pub ignore_failure: bool,
+    pub cmds: Vec<Command>,
+    pub terminus: Option<Word>,
+    // Terminus is appended to rather than truncated, ">>".
+    pub append: bool,
+    pub stderr: Option<Stderr>,
+    // File streamed into stdin of the first command, "<".
+    pub stdin: Option<Word>,
+    pub span: Span,
+}
+
+// Where stderr of every command in a pipeline goes.
+// Unlike the terminus it may follow any command, so that "a 2>&1 | tee log"
+// reads the way it does in a shell.
+#[derive(Debug, PartialEq)]
+pub enum Stderr {
+    // "2> file"
+    File(Word),
+    // "2>&1", or "&> file" along with a terminus.
+    Stdout,
+}
+
+impl Line {
+    // Every word in the line, in order.
+    pub fn words(&self) -> Vec<&Word> {
+        self.chains
+            .iter()
+            .flat_map(|chain| {
+                std::iter::once(&chain.first).chain(chain.rest.iter().map(|(_, p)| p))
+            })
+            .flat_map(|pipeline| {
+                let stderr = match &pipeline.stderr {
+                    Some(Stderr::File(word)) => Some(word),
+                    _ => None,
+                };
+                pipeline
+                    .stdin
+                    .iter()
+                    .chain(pipeline.cmds.iter().flat_map(|cmd| {
+                        cmd.assignments
+                            .iter()
+                            .map(|(_, value)| value)
+                            .chain(&cmd.words)
+                    }))
+                    .chain(&pipeline.terminus)
+                    .chain(stderr)
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Command {
+    // Environment variables set for just this command, "NAME=value cmd".
+    pub assignments: Vec<(String, Word)>,
+    // Name followed by arguments, never empty.
+    pub words: Vec<Word>,
+    pub span: Span,
+}
+
+// Parse a line of a run file into its syntax tree.
+pub fn parse_line(line: &str, number: usize) -> Result<Line, Error> {
+    let mut tokens = lex(line, number)?.into_iter().peekable();
+
+    let parallel = match tokens.peek() {
+        Some((Token::Word(word), _)) if word.bare() == Some("&") => {
+            tokens.next();
+            true
+        }
+        _ => false,
+    };
+
+    let end = Span {
+        line: number,
+        col: line.chars().count() + 1,
+        len: 0,
+    };
+
+    // Split into chains on ";", keeping where each one ends for reporting
+    // missing tokens.
+    let mut segments = vec![(vec![], end)];
+    for (token, span) in tokens {
+        let (segment, terminator) = segments.last_mut().expect("at least one segment");
+        match token {
+            Token::Semi => {
+                *terminator = span;
+                segments.push((vec![], end));
+            }
+            token => segment.push((token, span)),
+        }
+    }
+
+    let chains = segments
+        .into_iter()
+        .map(|(segment, terminator)| parse_chain(segment, terminator))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(Line { parallel, chains })
+}
+
+// Parse the tokens of a chain, splitting it into pipelines on "&&" and "||".
+// `end` locates what follows the chain, for reporting missing tokens.
+fn parse_chain(tokens: Vec<(Token, Span)>, end: Span) -> Result<Chain, Error> {
+    // Each pipeline's tokens, along with the operator preceding it and the
+    // span of whatever follows it.
+    let mut segments = vec![(None, vec![], end)];
+    for (token, span) in tokens {
+        let op = match token {
+            Token::AndAnd => Op::And,
+            Token::OrOr => Op::Or,
+            token => {
+                let (_, segment, _) = segments.last_mut().expect("at least one segment");
+                segment.push((token, span));
+                continue;
+            }
+        };
+        let (_, _, terminator) = segments.last_mut().expect("at least one segment");
+        *terminator = span;
+        segments.push((Some(op), vec![], end));
+    }
+
+    let mut segments = segments.into_iter();
+    let (_, tokens, terminator) = segments.next().expect("at least one segment");
+    let first = parse_pipeline(tokens, terminator)?;
+    let mut rest = vec![];
+    for (op, tokens, terminator) in segments {
+        // A failure can only be ignored for the chain as a whole.
+        if let Some((Token::Word(word), at)) = tokens.first() {
+            if word.bare() == Some("-") {
+                return Err(Error::parse("\"- \" must prefix the whole chain").at(*at));
+            }
+        }
+        rest.push((op.expect("operator"), parse_pipeline(tokens, terminator)?));
+    }
+    Ok(Chain { first, rest })
+}
+
+// Parse the tokens of a single pipeline.
+// `end` locates what follows the pipeline, for reporting missing tokens.
+fn parse_pipeline(tokens: Vec<(Token, Span)>, end: Span) -> Result<Pipeline, Error> {
+    let span = match (tokens.first(), tokens.last()) {
+        (Some((_, first)), Some((_, last))) => join(*first, *last),
+        _ => return Err(Error::parse("empty command").at(end)),
+    };
+    let mut tokens = tokens.into_iter().peekable();
+
+    let ignore_failure = match tokens.peek() {
+        Some((Token::Word(word), _)) if word.bare() == Some("-") => {
+            tokens.next();
+            true
+        }
+        _ => false,
+    };
+
+    let mut cmds = vec![];
+    let mut words = vec![];
+    let mut terminus = None;
+    let mut append = false;
+    let mut stderr = None;
+    let mut stdin = None;
+    // Whether the current command has been redirected.
+    let mut redirected = false;
+
+    while let Some((token, at)) = tokens.next() {
+        let redirection = !matches!(token, Token::Word(_) | Token::Pipe);
+        match token {
+            Token::Word(_) if redirected => {
+                return Err(Error::parse("redirection must come last in a command").at(at));
+            }
+            Token::Pipe if terminus.is_some() => {
+                return Err(Error::parse("redirection must come last in a pipeline").at(at));
+            }
+            Token::Word(word) => words.push(word),
+            Token::Pipe => {
+                cmds.push(command(std::mem::take(&mut words), at)?);
+                redirected = false;
+            }
+            Token::Great => set_once(&mut terminus, target(&mut tokens, end)?, at)?,
+            Token::GreatGreat => {
+                set_once(&mut terminus, target(&mut tokens, end)?, at)?;
+                append = true;
+            }
+            Token::Less if !cmds.is_empty() => {
+                return Err(Error::parse("input redirection must be in the first command").at(at));
+            }
+            Token::Less => set_once(&mut stdin, target(&mut tokens, end)?, at)?,
+            Token::ErrGreat => {
+                set_once(&mut stderr, Stderr::File(target(&mut tokens, end)?), at)?;
+            }
+            Token::ErrToOut => set_once(&mut stderr, Stderr::Stdout, at)?,
+            Token::AndGreat => {
+                set_once(&mut terminus, target(&mut tokens, end)?, at)?;
+                set_once(&mut stderr, Stderr::Stdout, at)?;
+            }
+            Token::Semi | Token::AndAnd | Token::OrOr => {
+                unreachable!("pipelines are split on semicolons and chain operators")
+            }
+        }
+        redirected |= redirection;
+    }
+    cmds.push(command(words, end)?);
+
+    Ok(Pipeline {
+        ignore_failure,
+        cmds,
+        terminus,
+        append,
+        stderr,
+        stdin,
+        span,
+    })
+}
+
+// Parse the file name following a redirection operator.
+fn target<Tokens>(tokens: &mut Tokens, end: Span) -> Result<Word, Error>
+where
+    Tokens: Iterator<Item = (Token, Span)>,
+{
+    match tokens.next() {
+        Some((Token::Word(word), _)) => Ok(word),
+        Some((_, at)) => Err(Error::parse("expected a file name").at(at)),
+        None => Err(Error::parse("expected a file name").at(end)),
+    }
+}
+
+// Each stream can only be redirected once.
+fn set_once<T>(slot: &mut Option<T>, value: T, at: Span) -> Result<(), Error> {
+    if slot.is_some() {
+        return Err(Error::parse("duplicate redirection").at(at));
+    }
+    *slot = Some(value);
+    Ok(())
+}
+
+// `at` locates the token following the command, for reporting empty commands.
+fn command(mut words: Vec<Word>, at: Span) -> Result<Command, Error> {
+    let span = match (words.first(), words.last()) {
+        (Some(first), Some(last)) => join(first.span, last.span),
+        _ => return Err(Error::parse("empty command").at(at)),
+    };
+    // Like a shell, only leading words are assignments.
+    let count = words.iter().take_while(|w| assignment(w).is_some()).count();
+    let assignments = words
+        .drain(..count)
+        .filter_map(|word| assignment(&word))
+        .collect();
+    if words.is_empty() {
+        return Err(Error::parse("expected a command after the assignment").at(at));
+    }
+    Ok(Command {
+        assignments,
+        words,
+        span,
+    })
+}
+
+// Split an assignment, "NAME=value", into its name and value.
+pub fn assignment(word: &Word) -> Option<(String, Word)> {
+    let (first, rest) = word.parts.split_first()?;
+    let (name, value) = match first {
+        Part::Lit(text) => text.split_once('=')?,
+        _ => return None,
+    };
+    let valid = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) || !name.chars().all(valid)
+    {
+        return None;
+    }
+    let mut parts = vec![];
+    if !value.is_empty() {
+        parts.push(Part::Lit(value.into()));
+    }
+    parts.extend(rest.iter().cloned());
+    Some((
+        name.into(),
+        Word {
+            parts,
+            span: word.span,
+        },
+    ))
+}
+
+// Span covering both spans, which must be on the same line.
+fn join(first: Span, last: Span) -> Span {
+    Span {
+        line: first.line,
+        col: first.col,
+        len: last.col + last.len - first.col,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn names(cmd: &Command) -> Vec<String> {
+        cmd.words
+            .iter()
+            .map(|w| {
+                w.parts
+                    .iter()
+                    .map(|p| match p {
+                        Part::Lit(s) | Part::Quoted(s) => s.clone(),
+                        Part::Var { name, .. } => format!("$({})", name),
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_line() {
+        let line = parse_line(r#"& - cat a | rg "x;y">out ; echo done"#, 1).expect("parsing");
+        assert!(line.parallel);
+        assert_eq!(line.chains.len(), 2);
+
+        let first = &line.chains[0].first;
+        assert!(first.ignore_failure);
+        assert_eq!(
+            first.cmds.iter().map(names).collect::<Vec<_>>(),
+            vec![vec!["cat", "a"], vec!["rg", "x;y"]]
+        );
+        assert_eq!(first.terminus.as_ref().and_then(|w| w.bare()), Some("out"));
+        assert_eq!(
+            first.span,
+            Span {
+                line: 1,
+                col: 3,
+                len: 22,
+            }
+        );
+
+        assert_eq!(first.stderr, None);
+
+        let second = &line.chains[1].first;
+        assert!(!second.ignore_failure);
+        assert_eq!(names(&second.cmds[0]), vec!["echo", "done"]);
+    }
+
+    #[test]
+    fn test_stderr_redirection() {
+        let stderr = |input| {
+            parse_line(input, 1)
+                .expect(input)
+                .chains
+                .remove(0)
+                .first
+                .stderr
+        };
+        assert_eq!(stderr("a 2>&1 | tee log"), Some(Stderr::Stdout));
+        assert_eq!(stderr("a | tee log 2>&1"), Some(Stderr::Stdout));
+        assert_eq!(stderr("a &> all.log"), Some(Stderr::Stdout));
+        match stderr("a 2> err.log") {
+            Some(Stderr::File(word)) => assert_eq!(word.bare(), Some("err.log")),
+            other => panic!("unexpected stderr: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_file_redirection() {
+        let line = parse_line("sort < in.txt | uniq >> out.txt", 1).expect("parsing");
+        let pipeline = &line.chains[0].first;
+        assert_eq!(pipeline.cmds.len(), 2);
+        assert_eq!(
+            pipeline.stdin.as_ref().and_then(|w| w.bare()),
+            Some("in.txt")
+        );
+        assert_eq!(
+            pipeline.terminus.as_ref().and_then(|w| w.bare()),
+            Some("out.txt")
+        );
+        assert!(pipeline.append);
+    }
+
+    #[test]
+    fn test_chain() {
+        let line = parse_line("- make && make install || echo failed; done", 1).expect("parsing");
+        assert_eq!(line.chains.len(), 2);
+
+        let chain = &line.chains[0];
+        assert!(chain.first.ignore_failure);
+        assert_eq!(names(&chain.first.cmds[0]), vec!["make"]);
+        let rest: Vec<_> = chain
+            .rest
+            .iter()
+            .map(|(op, pipeline)| (*op, names(&pipeline.cmds[0])))
+            .collect();
+        assert_eq!(
+            rest,
+            vec![
+                (Op::And, vec!["make".to_owned(), "install".to_owned()]),
+                (Op::Or, vec!["echo".to_owned(), "failed".to_owned()]),
+            ]
+        );
+        assert!(line.chains[1].rest.is_empty());
+    }
+
+    #[test]
+    fn test_assignments() {
+        let line = parse_line(r#"CC=gcc FLAGS="-O2 -g" make CFLAGS=x"#, 1).expect("parsing");
+        let cmd = &line.chains[0].first.cmds[0];
+        let assignments: Vec<_> = cmd
+            .assignments
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.parts.clone()))
+            .collect();
+        assert_eq!(
+            assignments,
+            vec![
+                ("CC", vec![Part::Lit("gcc".into())]),
+                ("FLAGS", vec![Part::Quoted("-O2 -g".into())]),
+            ]
+        );
+        assert_eq!(names(cmd), vec!["make", "CFLAGS=x"]);
+
+        let line = parse_line("=x a 1A=b", 1).expect("parsing");
+        assert!(line.chains[0].first.cmds[0].assignments.is_empty());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let cases = vec![
+            ("a | | b", 5),
+            ("a |", 4),
+            ("; a", 1),
+            ("a >", 4),
+            ("a > | b", 5),
+            ("a > out b", 9),
+            ("a > out &> all", 9),
+            ("a 2> err 2>&1", 10),
+            ("a 2>", 5),
+            ("a 2>&1 b", 8),
+            ("a | b < in", 7),
+            ("a < in < in", 8),
+            ("a >> out > out", 10),
+            ("a 2>&1 | b 2>&1", 12),
+            ("a &&", 5),
+            ("&& a", 1),
+            ("a && ; b", 6),
+            ("a || - b", 6),
+            ("A=1 | b", 5),
+        ];
+        for (input, col) in cases {
+            let err = parse_line(input, 1).expect_err(input);
+            assert_eq!(err.span.map(|s| s.col), Some(col), "{}", input);
+        }
+    }
+}
@@ -0,0 +1,525 @@
+// Builtins are commands run in-process rather than spawned, so that run files
+// work the same on machines without coreutils, eg Windows.
+//
+// Every builtin takes its flags before any operands, "--" ending the flags,
+// and reports errors as "name: what went wrong". The exception is echo, which
+// only knows -n and prints anything else.
+
+use crate::error::{Error, ErrorKind};
+use crate::parser::Cmd;
+use crate::pipeline::Context;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+pub const BUILTINS: &[&str] = &[
+    "cat", "cp", "echo", "env", "mkdir", "mv", "rm", "sleep", "touch", "which",
+];
+
+pub fn is_builtin(name: &str) -> bool {
+    BUILTINS.contains(&name)
+}
+
+// Whether `cmd` runs as a builtin. One given flags or operands it doesn't
+// support runs the installed program instead, if there is one, since that's
+// what the run file was written for.
+pub fn runs(ctx: &Context, cmd: &Cmd) -> bool {
+    is_builtin(&cmd.name) && (supports(cmd) || which(&cmd.name, &search_path(ctx, cmd)).is_none())
+}
+
+fn supports(cmd: &Cmd) -> bool {
+    let name = cmd.name.as_str();
+    match name {
+        "echo" => true,
+        "sleep" => matches!(cmd.args.as_slice(), [arg] if duration(arg).is_some()),
+        "env" => cmd.args.iter().all(|arg| assignment(arg).is_some()),
+        _ => flags(name, &cmd.args, allowed(name)).is_ok(),
+    }
+}
+
+// Flags the builtin `name` supports.
+fn allowed(name: &str) -> &'static str {
+    match name {
+        "mkdir" => "p",
+        "rm" => "rf",
+        "cp" => "r",
+        _ => "",
+    }
+}
+
+// Run the builtin `cmd`, reading from `stdin` and writing to `stdout`.
+pub fn run(
+    ctx: &Context,
+    cmd: &Cmd,
+    stdin: &mut dyn Read,
+    stdout: &mut dyn Write,
+) -> Result<(), Error> {
+    match builtin(ctx, cmd, stdin, stdout) {
+        // Whatever was reading the output stopped, eg "cat big.log | head",
+        // which like a shell isn't a failure.
+        Err(Error {
+            kind: ErrorKind::Io { source, .. },
+            ..
+        }) if source.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        result => result,
+    }
+}
+
+fn builtin(
+    ctx: &Context,
+    cmd: &Cmd,
+    stdin: &mut dyn Read,
+    stdout: &mut dyn Write,
+) -> Result<(), Error> {
+    let name = cmd.name.as_str();
+    let write_err = |e| Error::io(format!("{}: writing output", name), e);
+    match name {
+        "mkdir" => {
+            let (flags, dirs) = flags(name, &cmd.args, allowed(name))?;
+            operands(name, dirs, 1)?;
+            for dir in dirs {
+                let path = ctx.path(dir);
+                let result = if flags.has('p') {
+                    fs::create_dir_all(&path)
+                } else {
+                    fs::create_dir(&path)
+                };
+                result.map_err(|e| failed(name, "cannot create directory", dir, e))?;
+            }
+        }
+        "rm" => {
+            let (flags, files) = flags(name, &cmd.args, allowed(name))?;
+            operands(name, files, 1)?;
+            for file in files {
+                let path = ctx.path(file);
+                let is_dir = match fs::symlink_metadata(&path) {
+                    Ok(meta) => meta.is_dir(),
+                    Err(_) if flags.has('f') => continue,
+                    Err(e) => return Err(failed(name, "cannot remove", file, e)),
+                };
+                let result = match (is_dir, flags.has('r')) {
+                    (true, true) => fs::remove_dir_all(&path),
+                    (true, false) => {
+                        return Err(Error::builtin(
+                            name,
+                            format!("cannot remove {}: is a directory (use -r)", file),
+                        ));
+                    }
+                    (false, _) => fs::remove_file(&path),
+                };
+                result.map_err(|e| failed(name, "cannot remove", file, e))?;
+            }
+        }
+        "cp" | "mv" => {
+            let (flags, paths) = flags(name, &cmd.args, allowed(name))?;
+            operands(name, paths, 2)?;
+            let (dst, srcs) = paths.split_last().expect("at least two operands");
+            let dst = ctx.path(dst);
+            // Like coreutils, copying many files, or into an existing
+            // directory, puts them inside it.
+            let into = srcs.len() > 1 || dst.is_dir();
+            if srcs.len() > 1 && !dst.is_dir() {
+                return Err(Error::builtin(
+                    name,
+                    format!("target {} is not a directory", dst.display()),
+                ));
+            }
+            for src in srcs {
+                let from = ctx.path(src);
+                let to = match (into, from.file_name()) {
+                    (true, Some(file_name)) => dst.join(file_name),
+                    _ => dst.clone(),
+                };
+                let meta = fs::metadata(&from).map_err(|e| failed(name, "cannot stat", src, e))?;
+                let result = match name {
+                    "cp" if meta.is_dir() && !flags.has('r') => {
+                        return Err(Error::builtin(
+                            name,
+                            format!("omitting directory {} (use -r)", src),
+                        ));
+                    }
+                    "cp" => copy(&from, &to),
+                    _ => rename(&from, &to),
+                };
+                let action = if name == "cp" {
+                    "cannot copy"
+                } else {
+                    "cannot move"
+                };
+                result.map_err(|e| failed(name, action, src, e))?;
+            }
+        }
+        "touch" => {
+            let (_, files) = flags(name, &cmd.args, allowed(name))?;
+            operands(name, files, 1)?;
+            for file in files {
+                File::options()
+                    .create(true)
+                    .append(true)
+                    .open(ctx.path(file))
+                    .and_then(|f| f.set_modified(SystemTime::now()))
+                    .map_err(|e| failed(name, "cannot touch", file, e))?;
+            }
+        }
+        // Like POSIX echo, anything but a leading -n is printed as given,
+        // so that run files written for /bin/echo keep working.
+        "echo" => {
+            let (newline, words) = match cmd.args.split_first() {
+                Some((first, words)) if first == "-n" => ("", words),
+                _ => ("\n", cmd.args.as_slice()),
+            };
+            write!(stdout, "{}{}", words.join(" "), newline).map_err(write_err)?;
+        }
+        "cat" => {
+            let (_, files) = flags(name, &cmd.args, allowed(name))?;
+            if files.is_empty() {
+                io::copy(stdin, stdout).map_err(write_err)?;
+            }
+            for file in files {
+                if file == "-" {
+                    io::copy(stdin, stdout).map_err(write_err)?;
+                    continue;
+                }
+                let mut f =
+                    File::open(ctx.path(file)).map_err(|e| failed(name, "cannot open", file, e))?;
+                io::copy(&mut f, stdout).map_err(write_err)?;
+            }
+        }
+        // Like coreutils, "env NAME=value" prints the environment with the
+        // variables set. Given a command, the parser runs it with them.
+        "env" => {
+            let (_, args) = flags(name, &cmd.args, allowed(name))?;
+            let mut vars = env(ctx, cmd);
+            for arg in args {
+                let (key, value) = assignment(arg)
+                    .ok_or_else(|| Error::builtin(name, format!("unexpected argument: {}", arg)))?;
+                vars.insert(key.into(), value.into());
+            }
+            for (key, value) in vars {
+                writeln!(stdout, "{}={}", key, value).map_err(write_err)?;
+            }
+        }
+        "which" => {
+            let (_, names) = flags(name, &cmd.args, allowed(name))?;
+            operands(name, names, 1)?;
+            let path = search_path(ctx, cmd);
+            let mut missing = vec![];
+            for program in names {
+                if is_builtin(program) {
+                    writeln!(stdout, "{}: builtin", program).map_err(write_err)?;
+                    continue;
+                }
+                match which(program, &path) {
+                    Some(found) => writeln!(stdout, "{}", found.display()).map_err(write_err)?,
+                    None => missing.push(program.as_str()),
+                }
+            }
+            if !missing.is_empty() {
+                return Err(Error::builtin(
+                    name,
+                    format!("not found: {}", missing.join(", ")),
+                ));
+            }
+        }
+        "sleep" => {
+            let (_, args) = flags(name, &cmd.args, allowed(name))?;
+            let duration = match args {
+                [arg] => duration(arg)
+                    .ok_or_else(|| Error::builtin(name, format!("invalid duration: {}", arg)))?,
+                _ => return Err(Error::builtin(name, "expected a single duration")),
+            };
+            ctx.cancel.sleep(duration)?;
+        }
+        _ => unreachable!("not a builtin: {}", name),
+    }
+    Ok(())
+}
+
+// Flags given to a builtin.
+struct Flags(Vec<char>);
+
+impl Flags {
+    fn has(&self, flag: char) -> bool {
+        self.0.contains(&flag)
+    }
+}
+
+// Split leading flags, eg "-rf", from the operands, checking that every flag is
+// one of `allowed`.
+fn flags<'a>(
+    name: &str,
+    args: &'a [String],
+    allowed: &str,
+) -> Result<(Flags, &'a [String]), Error> {
+    let mut flags = vec![];
+    for (index, arg) in args.iter().enumerate() {
+        if arg == "--" {
+            return Ok((Flags(flags), &args[index + 1..]));
+        }
+        match arg.strip_prefix('-') {
+            Some(chars) if !chars.is_empty() => {
+                for c in chars.chars() {
+                    if !allowed.contains(c) {
+                        return Err(Error::builtin(name, format!("unknown flag: -{}", c)));
+                    }
+                    flags.push(c);
+                }
+            }
+            _ => return Ok((Flags(flags), &args[index..])),
+        }
+    }
+    Ok((Flags(flags), &[]))
+}
+
+fn operands(name: &str, operands: &[String], min: usize) -> Result<(), Error> {
+    if operands.len() < min {
+        return Err(Error::builtin(name, "missing operand"));
+    }
+    Ok(())
+}
+
+fn failed(name: &str, action: &str, path: &str, err: io::Error) -> Error {
+    Error::builtin(name, format!("{} {}: {}", action, path, err))
+}
+
+// Copy a file, or a directory and everything in it.
+fn copy(from: &Path, to: &Path) -> io::Result<()> {
+    if !from.is_dir() {
+        return fs::copy(from, to).map(|_| ());
+    }
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        copy(&entry.path(), &to.join(entry.file_name()))?;
+    }
+    Ok(())
+}
+
+// Rename falls back to copying when moving between file systems.
+fn rename(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            copy(from, to)?;
+            if from.is_dir() {
+                fs::remove_dir_all(from)
+            } else {
+                fs::remove_file(from)
+            }
+        }
+        result => result,
+    }
+}
+
+// The environment a command would see: the process's own, then anything
+// exported, then the command's own variables.
+fn env(ctx: &Context, cmd: &Cmd) -> BTreeMap<String, String> {
+    let mut vars: BTreeMap<String, String> = std::env::vars().collect();
+    for (key, value) in ctx.env.iter().chain(&cmd.env) {
+        vars.retain(|existing, _| !same_var(existing, key));
+        vars.insert(key.clone(), value.clone());
+    }
+    vars
+}
+
+// The PATH a command would search.
+fn search_path(ctx: &Context, cmd: &Cmd) -> String {
+    env(ctx, cmd)
+        .into_iter()
+        .find(|(key, _)| same_var(key, "PATH"))
+        .map(|(_, value)| value)
+        .unwrap_or_default()
+}
+
+// Whether two variable names are the same variable. Like std::env::var, names
+// are case-insensitive on Windows, where PATH is usually stored as "Path".
+fn same_var(a: &str, b: &str) -> bool {
+    if cfg!(windows) {
+        a.eq_ignore_ascii_case(b)
+    } else {
+        a == b
+    }
+}
+
+// Split "NAME=value".
+fn assignment(arg: &str) -> Option<(&str, &str)> {
+    arg.split_once('=').filter(|(name, _)| !name.is_empty())
+}
+
+// Find a program in `path`, trying each of PATHEXT's extensions on Windows.
+fn which(program: &str, path: &str) -> Option<PathBuf> {
+    let exts: Vec<String> = if cfg!(windows) {
+        let pathext = std::env::var("PATHEXT").unwrap_or_else(|_| ".EXE;.BAT;.CMD".into());
+        std::iter::once(String::new())
+            .chain(pathext.split(';').map(String::from))
+            .collect()
+    } else {
+        vec![String::new()]
+    };
+    std::env::split_paths(path)
+        .flat_map(|dir| {
+            exts.iter()
+                .map(move |ext| dir.join(format!("{}{}", program, ext)))
+        })
+        .find(|candidate| is_executable(candidate))
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    fs::metadata(path).is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+// Parse a duration in seconds, optionally fractional or suffixed with one of
+// "ms", "s", "m" or "h".
+pub fn duration(s: &str) -> Option<Duration> {
+    let (number, scale) = match s {
+        _ if s.ends_with("ms") => (&s[..s.len() - 2], 0.001),
+        _ if s.ends_with('s') => (&s[..s.len() - 1], 1.0),
+        _ if s.ends_with('m') => (&s[..s.len() - 1], 60.0),
+        _ if s.ends_with('h') => (&s[..s.len() - 1], 3600.0),
+        _ => (s, 1.0),
+    };
+    let seconds = number.parse::<f64>().ok()? * scale;
+    Duration::try_from_secs_f64(seconds).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::testing::TempDir;
+    use pretty_assertions::assert_eq;
+
+    fn cmd(line: &str) -> Cmd {
+        let mut words = line.split_whitespace().map(String::from);
+        Cmd {
+            name: words.next().expect("name"),
+            args: words.collect(),
+            env: vec![("RUN_TEST".into(), "1".into())],
+            globs: vec![],
+        }
+    }
+
+    // Run a builtin in `dir`, returning its output.
+    fn run_in(dir: &Path, line: &str) -> Result<String, Error> {
+        let ctx = Context {
+            dir: Some(dir.to_owned()),
+            ..Context::default()
+        };
+        let mut stdout = vec![];
+        run(&ctx, &cmd(line), &mut io::empty(), &mut stdout)?;
+        Ok(String::from_utf8(stdout).expect("utf8 output"))
+    }
+
+    #[test]
+    fn test_flags() {
+        let args: Vec<String> = vec!["-rf".into(), "--".into(), "-x".into()];
+        let (given, rest) = flags("rm", &args, "rf").expect("flags");
+        assert!(given.has('r') && given.has('f'));
+        assert_eq!(rest, &["-x".to_owned()]);
+
+        let err = flags("rm", &args[2..], "rf").err().expect("unknown flag");
+        assert_eq!(err.to_string(), "rm: unknown flag: -x");
+    }
+
+    #[test]
+    fn test_file_builtins() {
+        let dir = TempDir::new("builtins");
+        let run = |line| run_in(&dir, line);
+
+        run("mkdir -p a/b/c").expect("mkdir -p");
+        assert!(run("mkdir a").is_err());
+        run("touch a/b/c/one.txt a/two.txt").expect("touch");
+        run("cp -r a copy").expect("cp -r");
+        assert!(dir.join("copy/b/c/one.txt").is_file());
+        assert_eq!(
+            run("cp a elsewhere").err().map(|e| e.to_string()),
+            Some("cp: omitting directory a (use -r)".into())
+        );
+
+        run("mkdir moved").expect("mkdir");
+        run("mv copy/two.txt copy/b moved").expect("mv");
+        assert!(dir.join("moved/two.txt").is_file());
+        assert!(dir.join("moved/b/c/one.txt").is_file());
+        run("touch three.txt").expect("touch");
+        let err = run("mv three.txt missing/three.txt").expect_err("mv into missing");
+        assert!(
+            err.to_string().starts_with("mv: cannot move three.txt: "),
+            "{}",
+            err
+        );
+
+        assert!(run("rm moved").is_err());
+        run("rm -r moved a/two.txt").expect("rm -r");
+        assert!(!dir.join("moved").exists());
+        assert!(!dir.join("a/two.txt").exists());
+        assert!(run("rm missing").is_err());
+        run("rm -f missing").expect("rm -f");
+
+        fs::write(dir.join("hello.txt"), "hello\n").expect("writing");
+        assert_eq!(
+            run("cat hello.txt hello.txt").expect("cat"),
+            "hello\nhello\n"
+        );
+    }
+
+    #[test]
+    fn test_output_builtins() {
+        let dir = std::env::temp_dir();
+        assert_eq!(run_in(&dir, "echo a  b").expect("echo"), "a b\n");
+        assert_eq!(run_in(&dir, "echo -n a").expect("echo -n"), "a");
+        assert_eq!(
+            run_in(&dir, "echo -e -DFOO -- a").expect("echo flags"),
+            "-e -DFOO -- a\n"
+        );
+        assert!(run_in(&dir, "env")
+            .expect("env")
+            .lines()
+            .any(|l| l == "RUN_TEST=1"));
+        assert_eq!(
+            run_in(&dir, "which echo").expect("which"),
+            "echo: builtin\n"
+        );
+        assert!(run_in(&dir, "which run-no-such-program").is_err());
+        assert!(run_in(&dir, "env RUN_OTHER=2")
+            .expect("env with variables")
+            .lines()
+            .any(|l| l == "RUN_OTHER=2"));
+    }
+
+    #[test]
+    fn test_supports() {
+        for line in ["cat a", "cp -r a b", "echo -e a", "env A=1", "sleep 1s"] {
+            assert!(supports(&cmd(line)), "{}", line);
+        }
+        for line in [
+            "cat -n a",
+            "mkdir -m 700 a",
+            "touch -d now a",
+            "env -i",
+            "sleep inf",
+        ] {
+            assert!(!supports(&cmd(line)), "{}", line);
+        }
+    }
+
+    #[test]
+    fn test_sleep() {
+        assert_eq!(duration("1.5"), Some(Duration::from_millis(1500)));
+        assert_eq!(duration("2m"), Some(Duration::from_secs(120)));
+        assert_eq!(duration("10ms"), Some(Duration::from_millis(10)));
+        assert_eq!(duration("soon"), None);
+
+        let ctx = Context::default();
+        ctx.cancel.cancel();
+        let err =
+            run(&ctx, &cmd("sleep 60"), &mut io::empty(), &mut io::sink()).expect_err("cancelled");
+        assert!(matches!(err.kind, ErrorKind::Cancelled));
+    }
+}
@@ -1,25 +1,135 @@
+use crate::builtin;
+use crate::error::Error;
+use crate::incremental::CACHE_FILE;
+use crate::pipeline::Context;
 use std::iter::Peekable;
+use std::path::Path;
+use std::time::Duration;
 
 #[derive(Default, Debug)]
 pub struct Config {
     pub dry_run: bool,
+    // Print usage generated from the run file instead of running it.
+    pub help: bool,
+    // List the run file's tasks instead of running any.
+    pub list: bool,
+    // Maximum number of concurrent jobs, unlimited by default.
+    pub jobs: Option<usize>,
+    // What to do with a glob pattern that matches nothing.
+    pub no_match: NoMatch,
+    // Run incremental items even when they're up to date.
+    pub force: bool,
+    // Check incremental items by hashing their inputs rather than by
+    // modification time.
+    pub hash: bool,
+    // Rerun whenever the files items depend on change.
+    pub watch: bool,
+    // Patterns of other files to watch.
+    pub watch_globs: Vec<String>,
+    // How long each command may run for, unless it sets its own timeout.
+    pub timeout: Option<Duration>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum NoMatch {
+    // Pass the pattern through as written, like a shell.
+    #[default]
+    Keep,
+    // Fail the command.
+    Error,
 }
 
 impl Config {
+    // The context items of the run file at `path` run in, as configured.
+    // Hashes are cached beside the run file, wherever run was started from.
+    pub fn context(&self, path: Option<&Path>) -> Context {
+        let cache = path.map_or(Path::new(CACHE_FILE).into(), |path| {
+            path.with_file_name(CACHE_FILE)
+        });
+        Context {
+            jobs: self.jobs,
+            no_match: self.no_match,
+            force: self.force,
+            cache: self.hash.then_some(cache),
+            timeout: self.timeout,
+            ..Context::default()
+        }
+    }
+
     /// Consumes a stream of strings and parses flags into config values.
     /// Only actually consumes the values recognised by Config.
     /// Returns on the first unrecognised value.
-    pub fn from_args<Args, Str>(args: &mut Peekable<Args>) -> Self
+    pub fn from_args<Args, Str>(args: &mut Peekable<Args>) -> Result<Self, Error>
     where
         Args: Iterator<Item = Str>,
         Str: AsRef<str>,
     {
         let mut config = Config::default();
+        config.update_from_args(args)?;
+        Ok(config)
+    }
 
+    /// Like `from_args`, but for flags given after others already parsed.
+    pub fn update_from_args<Args, Str>(&mut self, args: &mut Peekable<Args>) -> Result<(), Error>
+    where
+        Args: Iterator<Item = Str>,
+        Str: AsRef<str>,
+    {
         while let Some(arg) = args.peek() {
             match arg.as_ref() {
                 "--dry-run" | "--dry" => {
-                    config.dry_run = true;
+                    self.dry_run = true;
+                }
+                "--help" => {
+                    self.help = true;
+                }
+                "--list" => {
+                    self.list = true;
+                }
+                "--force" => {
+                    self.force = true;
+                }
+                "--hash" => {
+                    self.hash = true;
+                }
+                "--watch" => {
+                    self.watch = true;
+                }
+                "--watch-glob" => {
+                    args.next();
+                    let glob = args
+                        .peek()
+                        .map(|glob| glob.as_ref().to_owned())
+                        .ok_or_else(|| Error::parse("--watch-glob expects a pattern"))?;
+                    glob::Pattern::new(&glob).map_err(|e| {
+                        Error::parse(format!("invalid watch pattern {}: {}", glob, e))
+                    })?;
+                    self.watch_globs.push(glob);
+                }
+                "--jobs" => {
+                    args.next();
+                    let jobs = args
+                        .peek()
+                        .and_then(|n| n.as_ref().parse::<usize>().ok())
+                        .filter(|n| *n > 0)
+                        .ok_or_else(|| Error::parse("--jobs expects a positive number"))?;
+                    self.jobs = Some(jobs);
+                }
+                "--timeout" => {
+                    args.next();
+                    let timeout = args
+                        .peek()
+                        .and_then(|t| builtin::duration(t.as_ref()))
+                        .ok_or_else(|| Error::parse("--timeout expects a duration, eg 30s"))?;
+                    self.timeout = Some(timeout);
+                }
+                "--no-match" => {
+                    args.next();
+                    self.no_match = match args.peek().map(|p| p.as_ref()) {
+                        Some("keep") => NoMatch::Keep,
+                        Some("error") => NoMatch::Error,
+                        _ => return Err(Error::parse("--no-match expects keep or error")),
+                    };
                 }
                 _ => {
                     break;
@@ -27,6 +137,141 @@ impl Config {
             }
             args.next();
         }
-        config
+        Ok(())
+    }
+
+    /// Like `update_from_args`, but consumes every flag among `args` rather
+    /// than stopping at the first unrecognised value, returning the others.
+    /// A "--name value" pair is left for the environment as a variable, but
+    /// "--name" without a value can only be a mistaken flag.
+    pub fn update_from_mixed_args<Args, Str>(
+        &mut self,
+        args: &mut Peekable<Args>,
+    ) -> Result<Vec<String>, Error>
+    where
+        Args: Iterator<Item = Str>,
+        Str: AsRef<str>,
+    {
+        let mut rest = vec![];
+        loop {
+            self.update_from_args(args)?;
+            let arg = match args.next() {
+                Some(arg) => arg.as_ref().to_owned(),
+                None => return Ok(rest),
+            };
+            if arg.starts_with('-') {
+                match args.next_if(|value| !value.as_ref().starts_with('-')) {
+                    Some(value) => {
+                        rest.push(arg);
+                        rest.push(value.as_ref().to_owned());
+                    }
+                    None if arg.starts_with("--") => {
+                        return Err(Error::parse(format!("unknown flag: {}", arg)))
+                    }
+                    None => rest.push(arg),
+                }
+            } else {
+                rest.push(arg);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> (Result<Config, Error>, Vec<String>) {
+        let mut args = args.iter().peekable();
+        let config = Config::from_args(&mut args);
+        (config, args.map(|s| s.to_string()).collect())
+    }
+
+    #[test]
+    fn test_config_flags() {
+        let (config, rest) = parse(&[
+            "--dry",
+            "--jobs",
+            "4",
+            "--help",
+            "--list",
+            "--force",
+            "--hash",
+            "--watch",
+            "--watch-glob",
+            "src/*.c",
+            "--timeout",
+            "1.5m",
+            "--no-match",
+            "error",
+            "release",
+            "--jobs",
+        ]);
+        let config = config.expect("parsing");
+        assert!(config.dry_run);
+        assert!(config.help);
+        assert!(config.list);
+        assert!(config.force && config.hash);
+        assert!(config.watch);
+        assert_eq!(config.watch_globs, vec!["src/*.c"]);
+        assert_eq!(config.jobs, Some(4));
+        assert_eq!(config.timeout, Some(Duration::from_secs(90)));
+        assert_eq!(config.no_match, NoMatch::Error);
+        assert_eq!(rest, vec!["release", "--jobs"]);
+    }
+
+    #[test]
+    fn test_cache_beside_run_file() {
+        let (config, _) = parse(&["--hash"]);
+        let ctx = config
+            .expect("parsing")
+            .context(Some(Path::new("sub/build.run")));
+        assert_eq!(ctx.cache, Some(Path::new("sub").join(CACHE_FILE)));
+    }
+
+    #[test]
+    fn test_mixed_flags() {
+        let args = [
+            "-cc",
+            "gcc",
+            "--dry",
+            "--Version",
+            "1",
+            "main.c",
+            "--jobs",
+            "2",
+        ];
+        let mut config = Config::default();
+        let rest = config
+            .update_from_mixed_args(&mut args.iter().peekable())
+            .expect("parsing");
+        assert!(config.dry_run);
+        assert_eq!(config.jobs, Some(2));
+        assert_eq!(rest, vec!["-cc", "gcc", "--Version", "1", "main.c"]);
+
+        let err = config
+            .update_from_mixed_args(&mut ["--bogus", "-x", "1"].iter().peekable())
+            .expect_err("unknown flag");
+        assert_eq!(err.to_string(), "unknown flag: --bogus");
+    }
+
+    #[test]
+    fn test_variables_arent_flags() {
+        for flag in ["-j", "-h", "-l", "-f", "-w"] {
+            let (config, rest) = parse(&[flag, "4"]);
+            config.expect("parsing");
+            assert_eq!(rest, vec![flag, "4"]);
+        }
+    }
+
+    #[test]
+    fn test_invalid_flags() {
+        assert!(parse(&["--jobs", "zero"]).0.is_err());
+        assert!(parse(&["--jobs", "0"]).0.is_err());
+        assert!(parse(&["--jobs"]).0.is_err());
+        assert!(parse(&["--no-match", "ignore"]).0.is_err());
+        assert!(parse(&["--watch-glob"]).0.is_err());
+        assert!(parse(&["--timeout", "soon"]).0.is_err());
+        assert!(parse(&["--watch-glob", "[a"]).0.is_err());
     }
 }
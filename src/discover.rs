@@ -0,0 +1,123 @@
+// Discovery finds the run file to use when none is given, the way make finds
+// its makefile: the nearest directory, from the current one up, that has a
+// "Runfile" or ".run" files in it.
+//
+// If that directory has several, the one defining the requested task is used,
+// then the Runfile. Otherwise the choice is left to the user.
+
+use crate::error::Error;
+use crate::task::RunFile;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const RUNFILE: &str = "Runfile";
+
+pub fn is_runfile(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.eq_ignore_ascii_case(RUNFILE))
+}
+
+// Run files in `dir`, the Runfile first and the rest by name.
+pub fn candidates(dir: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.is_file() && (is_runfile(path) || path.extension().is_some_and(|e| e == "run"))
+        })
+        .collect();
+    files.sort_by_key(|path| (!is_runfile(path), path.clone()));
+    files
+}
+
+// The nearest directory, from `start` up, that has run files, along with them.
+pub fn nearest(start: &Path) -> Option<(PathBuf, Vec<PathBuf>)> {
+    start
+        .ancestors()
+        .map(|dir| (dir.to_owned(), candidates(dir)))
+        .find(|(_, files)| !files.is_empty())
+}
+
+// Find the run file to use from `start`, for the given task if any.
+pub fn find(start: &Path, task: Option<&str>) -> Result<PathBuf, Error> {
+    let (dir, mut files) = nearest(start).ok_or_else(|| {
+        Error::parse(format!(
+            "no {} or .run file in {} or any directory above it",
+            RUNFILE,
+            start.display()
+        ))
+    })?;
+    if let Some(task) = task {
+        let defining: Vec<PathBuf> = files
+            .iter()
+            .filter(|path| defines(path, task))
+            .cloned()
+            .collect();
+        if !defining.is_empty() {
+            files = defining;
+        }
+    }
+    if files.len() == 1 || is_runfile(&files[0]) {
+        return Ok(files.swap_remove(0));
+    }
+
+    let names: Vec<String> = files
+        .iter()
+        .filter_map(|path| path.file_name())
+        .map(|name| format!("  {}", name.to_string_lossy()))
+        .collect();
+    Err(Error::parse(format!(
+        "several run files in {}, pick one:\n{}",
+        dir.display(),
+        names.join("\n")
+    )))
+}
+
+// Whether the run file at `path` defines `task`.
+// Files that can't be read or parsed don't define anything.
+fn defines(path: &Path, task: &str) -> bool {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|text| RunFile::parse(&text).ok())
+        .is_some_and(|run_file| run_file.tasks.iter().any(|t| t.name == task))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::testing::TempDir;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_find() {
+        let dir = TempDir::new("discover");
+        let nested = dir.join("project/src/deep");
+        fs::create_dir_all(&nested).expect("creating dir");
+        let write = |name: &str, text: &str| {
+            fs::write(dir.join(name), text).expect("writing run file");
+        };
+        let found = |task| {
+            find(&nested, task)
+                .map(|path| path.strip_prefix(&dir).expect("in dir").to_owned())
+                .map_err(|err| err.to_string())
+        };
+
+        write("project/build.run", "cargo build\n");
+        assert_eq!(found(None), Ok("project/build.run".into()));
+
+        write("project/test.run", "test:\n    cargo test\n");
+        let err = found(None).expect_err("ambiguous");
+        assert!(
+            err.ends_with("pick one:\n  build.run\n  test.run"),
+            "{}",
+            err
+        );
+        assert_eq!(found(Some("test")), Ok("project/test.run".into()));
+
+        write("project/Runfile", "cargo check\n");
+        assert_eq!(found(None), Ok("project/Runfile".into()));
+        assert_eq!(found(Some("missing")), Ok("project/Runfile".into()));
+    }
+}
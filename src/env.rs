@@ -1,16 +1,17 @@
+use crate::error::Error;
+use crate::params::Param;
 use crate::util::SplitWords;
 use std::collections::HashMap;
-use std::error::Error;
 use std::str::FromStr;
 
-#[derive(Debug, Default, PartialEq)]
+#[derive(Clone, Debug, Default, PartialEq)]
 pub struct Environment {
     pub named: HashMap<String, String>,
     pub positional: Vec<String>,
 }
 
 impl FromStr for Environment {
-    type Err = Box<dyn Error>;
+    type Err = Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let mut env = Environment {
@@ -32,7 +33,7 @@ impl FromStr for Environment {
                 let name = arg.trim_matches('-').to_owned();
                 if let Some(value) = iter.next() {
                     if value.starts_with("-") {
-                        return Err(format!("{} is missing a value", arg).into());
+                        return Err(Error::parse(format!("{} is missing a value", arg)));
                     }
                     env.named.insert(name, value);
                 }
@@ -45,6 +46,78 @@ impl FromStr for Environment {
     }
 }
 
+impl Environment {
+    // Parse arguments as given on the command line, each its own word.
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Self, Error> {
+        // Wrap each unique argument in quotes for the environment parser.
+        // Quotes get stripped on entry, so we add them back.
+        // #perf
+        let s: String = args.fold(String::new(), |mut buf, next| {
+            buf.push('"');
+            buf.push_str(&next);
+            buf.push('"');
+            buf.push(' ');
+            buf
+        });
+        s.parse()
+    }
+
+    // Validate arguments against the parameters a run file declares, filling
+    // in defaults for any that weren't given.
+    // Files that declare no parameters accept anything.
+    pub fn validate(&mut self, params: &[Param]) -> Result<(), Error> {
+        if params.is_empty() {
+            return Ok(());
+        }
+        let mut errors = vec![];
+
+        for name in self.named.keys() {
+            if !params.iter().any(|p| &p.name == name) {
+                errors.push(Error::parse(format!("unknown parameter: -{}", name)));
+            }
+        }
+        let positions = params.iter().filter_map(Param::position).max().unwrap_or(0);
+        for arg in self.positional.iter().skip(positions) {
+            errors.push(Error::parse(format!("unexpected argument: {}", arg)));
+        }
+
+        // Positional parameters in order, so that defaults fill them in order.
+        let mut params: Vec<&Param> = params.iter().collect();
+        params.sort_by_key(|p| p.position());
+        for param in params {
+            let value = match param.position() {
+                Some(index) => self.positional.get(index - 1),
+                None => self.named.get(&param.name),
+            };
+            match (value, &param.default) {
+                (Some(value), _) => {
+                    if let Err(err) = param.check(value) {
+                        errors.push(err);
+                    }
+                }
+                (None, Some(default)) => match param.position() {
+                    // Otherwise an earlier argument is missing, which is
+                    // reported already.
+                    Some(index) if index == self.positional.len() + 1 => {
+                        self.positional.push(default.clone());
+                    }
+                    Some(_) => {}
+                    None => {
+                        self.named.insert(param.name.clone(), default.clone());
+                    }
+                },
+                (None, None) => {
+                    errors.push(Error::parse(format!("missing parameter: {}", param.flag())));
+                }
+            }
+        }
+
+        // Sorted for stable output, since named arguments are unordered.
+        errors.sort_by_key(|e| e.to_string());
+        Error::all(errors)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -66,4 +139,45 @@ mod tests {
         let got = Environment::from_str(input).unwrap();
         assert_eq!(got, want);
     }
+
+    #[test]
+    fn test_validate() {
+        let params: Vec<Param> = vec![
+            "param cc = gcc",
+            "param jobs: int",
+            "param profile: enum(debug, release) = debug",
+            "param 1: path",
+            "param 2 = out",
+        ]
+        .into_iter()
+        .map(|line| Param::parse(line, 1).expect("declaration").expect(line))
+        .collect();
+
+        let mut env = Environment::from_str("-jobs 4 main.c").unwrap();
+        env.validate(&params).expect("valid arguments");
+        assert_eq!(env.named.get("cc").map(String::as_str), Some("gcc"));
+        assert_eq!(env.named.get("profile").map(String::as_str), Some("debug"));
+        assert_eq!(env.positional, vec!["main.c", "out"]);
+
+        let mut env = Environment::from_str("-jobs many -profile fast -lib x a b c").unwrap();
+        let err = env.validate(&params).expect_err("invalid arguments");
+        assert_eq!(
+            err.to_string(),
+            r#"-jobs: expected int, got "many"
+-profile: expected debug|release, got "fast"
+unexpected argument: c
+unknown parameter: -lib"#
+        );
+
+        let mut env = Environment::default();
+        let err = env.validate(&params).expect_err("missing arguments");
+        assert_eq!(
+            err.to_string(),
+            "missing parameter: -jobs
+missing parameter: <1>"
+        );
+
+        let mut env = Environment::from_str("anything -goes here").unwrap();
+        env.validate(&[]).expect("undeclared parameters");
+    }
 }
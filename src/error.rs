@@ -0,0 +1,285 @@
+use std::fmt;
+use std::io;
+use std::process::ExitStatus;
+use std::time::Duration;
+
+// Span locates a piece of a run file.
+// Lines and columns are 1-based, columns and length count chars.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Span {
+    pub line: usize,
+    pub col: usize,
+    pub len: usize,
+}
+
+impl Span {
+    // Span of `part`, which must be a slice of `line`.
+    pub fn of(number: usize, line: &str, part: &str) -> Self {
+        let offset = (part.as_ptr() as usize).saturating_sub(line.as_ptr() as usize);
+        let offset = offset.min(line.len());
+        Span {
+            line: number,
+            col: line[..offset].chars().count() + 1,
+            len: part.chars().count(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ErrorKind {
+    // Malformed run file, tasks or command line.
+    Parse(String),
+    // A variable was referenced without a value, optionally with a message
+    // from the run file explaining what to do about it.
+    Variable { name: String, msg: Option<String> },
+    // A command couldn't be started.
+    Spawn { cmd: String, source: io::Error },
+    // A command ran to completion but exited unsuccessfully.
+    Exit { cmd: String, status: ExitStatus },
+    // A builtin command failed.
+    Builtin { cmd: String, msg: String },
+    // Any other I/O failure, along with what was being done at the time.
+    Io { context: String, source: io::Error },
+    // Killed before completion, eg because a sibling job failed.
+    Cancelled,
+    // Killed for running longer than allowed, along with the commands that
+    // were still running.
+    Timeout { cmd: String, after: Duration },
+    // Several errors, reported together.
+    Many(Vec<Error>),
+}
+
+// Error is every failure a run can encounter.
+// If the failure can be traced to the run file, it carries the span and, once
+// attached via `with_source`, the file name and offending line for reporting.
+#[derive(Debug)]
+pub struct Error {
+    pub kind: ErrorKind,
+    pub span: Option<Span>,
+    // File name and the text of the line the span points at.
+    source: Option<Box<(String, String)>>,
+}
+
+impl Error {
+    pub fn new(kind: ErrorKind) -> Self {
+        Error {
+            kind,
+            span: None,
+            source: None,
+        }
+    }
+
+    pub fn parse(msg: impl Into<String>) -> Self {
+        Error::new(ErrorKind::Parse(msg.into()))
+    }
+
+    pub fn io(context: impl Into<String>, source: io::Error) -> Self {
+        Error::new(ErrorKind::Io {
+            context: context.into(),
+            source,
+        })
+    }
+
+    pub fn builtin(cmd: impl Into<String>, msg: impl fmt::Display) -> Self {
+        Error::new(ErrorKind::Builtin {
+            cmd: cmd.into(),
+            msg: msg.to_string(),
+        })
+    }
+
+    pub fn variable(name: impl Into<String>, msg: Option<String>) -> Self {
+        Error::new(ErrorKind::Variable {
+            name: name.into(),
+            msg,
+        })
+    }
+
+    // Combine errors so that they can all be reported at once.
+    pub fn all(mut errors: Vec<Error>) -> Result<(), Error> {
+        match errors.len() {
+            0 => Ok(()),
+            1 => Err(errors.remove(0)),
+            _ => Err(Error::new(ErrorKind::Many(errors))),
+        }
+    }
+
+    // Locate the error, unless it already points somewhere more specific.
+    pub fn at(mut self, span: Span) -> Self {
+        self.span.get_or_insert(span);
+        self
+    }
+
+    // Attach the run file the error's span refers to.
+    pub fn with_source(mut self, file: &str, src: &str) -> Self {
+        if let ErrorKind::Many(errors) = self.kind {
+            let errors = errors.into_iter().map(|e| e.with_source(file, src));
+            self.kind = ErrorKind::Many(errors.collect());
+            return self;
+        }
+        if let (None, Some(span)) = (&self.source, self.span) {
+            let text = src.lines().nth(span.line.saturating_sub(1)).unwrap_or("");
+            self.source = Some(Box::new((file.into(), text.into())));
+        }
+        self
+    }
+
+    // Exit code for the run process to report this error with.
+    // Processes killed by a signal have no code, so report a generic failure.
+    pub fn code(&self) -> i32 {
+        match &self.kind {
+            ErrorKind::Exit { status, .. } => status.code().unwrap_or(1),
+            // As reported by coreutils' timeout.
+            ErrorKind::Timeout { .. } => 124,
+            _ => 1,
+        }
+    }
+
+    // Report renders the error for the user, with a caret-annotated snippet of
+    // the offending line if there is one:
+    //
+    //  error: no value specified for argument: cc
+    //   --> build.run:2:1
+    //    |
+    //  2 | $(cc) -o tmp.exe foo.c
+    //    | ^^^^^
+    pub fn report(&self) -> String {
+        if let ErrorKind::Many(errors) = &self.kind {
+            let reports: Vec<_> = errors.iter().map(Error::report).collect();
+            return reports.join("\n\n");
+        }
+        let mut report = format!("error: {}", self);
+        if let (Some(span), Some(source)) = (self.span, &self.source) {
+            let (file, text) = source.as_ref();
+            let number = span.line.to_string();
+            let gutter = " ".repeat(number.len());
+            // Tabs are kept so that the carets line up with the source.
+            let indent: String = text
+                .chars()
+                .take(span.col - 1)
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            report.push_str(&format!(
+                "\n --> {}:{}:{}\n{} |\n{} | {}\n{} | {}{}",
+                file,
+                span.line,
+                span.col,
+                gutter,
+                number,
+                text,
+                gutter,
+                indent,
+                "^".repeat(span.len.max(1)),
+            ));
+        }
+        report
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.kind {
+            ErrorKind::Parse(msg) => write!(f, "{}", msg),
+            ErrorKind::Variable { name, msg: None } => {
+                write!(f, "no value specified for argument: {}", name)
+            }
+            ErrorKind::Variable {
+                name,
+                msg: Some(msg),
+            } => write!(f, "{}: {}", name, msg),
+            ErrorKind::Spawn { cmd, source } => write!(f, "{}: {}", cmd, source),
+            ErrorKind::Exit { cmd, status } => write!(f, "{}: {}", cmd, status),
+            ErrorKind::Builtin { cmd, msg } => write!(f, "{}: {}", cmd, msg),
+            ErrorKind::Io { context, source } => write!(f, "{}: {}", context, source),
+            ErrorKind::Cancelled => write!(f, "cancelled"),
+            ErrorKind::Timeout { cmd, after } => write!(f, "{}: timed out after {:?}", cmd, after),
+            ErrorKind::Many(errors) => {
+                let msgs: Vec<_> = errors.iter().map(Error::to_string).collect();
+                write!(f, "{}", msgs.join("\n"))
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self.kind {
+            ErrorKind::Spawn { source, .. } | ErrorKind::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::io("writing output", err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_report() {
+        let src = "// Build.\n  $(cc) -o tmp.exe foo.c\n";
+        let err = Error::variable("cc", None)
+            .at(Span {
+                line: 2,
+                col: 3,
+                len: 5,
+            })
+            .with_source("build.run", src);
+        let want = r#"error: no value specified for argument: cc
+ --> build.run:2:3
+  |
+2 |   $(cc) -o tmp.exe foo.c
+  |   ^^^^^"#;
+        assert_eq!(err.report(), want);
+    }
+
+    #[test]
+    fn test_report_without_span() {
+        let err = Error::parse("-cc is missing a value").with_source("build.run", "");
+        assert_eq!(err.report(), "error: -cc is missing a value");
+    }
+
+    #[test]
+    fn test_report_many() {
+        let src = "$(cc) $(lib:?pass -lib)\n";
+        let span = |col, len| Span { line: 1, col, len };
+        let err = Error::all(vec![
+            Error::variable("cc", None).at(span(1, 5)),
+            Error::variable("lib", Some("pass -lib".into())).at(span(7, 17)),
+        ])
+        .expect_err("two errors")
+        .with_source("build.run", src);
+        let want = r#"error: no value specified for argument: cc
+ --> build.run:1:1
+  |
+1 | $(cc) $(lib:?pass -lib)
+  | ^^^^^
+
+error: lib: pass -lib
+ --> build.run:1:7
+  |
+1 | $(cc) $(lib:?pass -lib)
+  |       ^^^^^^^^^^^^^^^^^"#;
+        assert_eq!(err.report(), want);
+        assert!(Error::all(vec![]).is_ok());
+    }
+
+    #[test]
+    fn test_span_of() {
+        let line = "  héllo | world";
+        let part = &line[line.find("world").unwrap()..];
+        assert_eq!(
+            Span::of(3, line, part),
+            Span {
+                line: 3,
+                col: 11,
+                len: 5,
+            }
+        );
+    }
+}
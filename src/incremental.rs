@@ -0,0 +1,205 @@
+// Incremental items are skipped when their declared outputs are up to date
+// with their declared inputs.
+//
+// By default that's when every output was modified after every input, like
+// make. With "--hash" it's when the outputs exist and the inputs' contents
+// hash the same as when the items last succeeded, as recorded in a cache file,
+// ".run-cache" in the run file's directory. Either way "--force" runs them
+// regardless.
+
+use crate::error::Error;
+use crate::pipeline::Context;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+pub const CACHE_FILE: &str = ".run-cache";
+
+// Whether the items, identified by their labels, can be skipped.
+pub fn up_to_date(
+    ctx: &Context,
+    inputs: &[String],
+    outputs: &[PathBuf],
+    labels: &[String],
+) -> Result<bool, Error> {
+    if ctx.force {
+        return Ok(false);
+    }
+    let outputs: Option<Vec<SystemTime>> = outputs
+        .iter()
+        .map(|path| fs::metadata(ctx.path(path)).and_then(|m| m.modified()).ok())
+        .collect();
+    let outputs = match outputs {
+        Some(outputs) => outputs,
+        None => return Ok(false),
+    };
+    let inputs = files(ctx, inputs)?;
+
+    if let Some(cache) = &ctx.cache {
+        let cache = load(cache)?;
+        return Ok(cache.get(&key(ctx, inputs.len(), labels)) == Some(&hash(&inputs)?));
+    }
+    let newest = inputs
+        .iter()
+        .map(|path| fs::metadata(path).and_then(|m| m.modified()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| Error::io("reading inputs", e))?
+        .into_iter()
+        .max();
+    let oldest = outputs.into_iter().min();
+    Ok(match (newest, oldest) {
+        (Some(newest), Some(oldest)) => newest <= oldest,
+        // Outputs that exist are all there is to check.
+        (None, Some(_)) => true,
+        // Without outputs there's nothing to be up to date.
+        (_, None) => false,
+    })
+}
+
+// Record that the items succeeded with the inputs as they are now.
+pub fn record(ctx: &Context, inputs: &[String], labels: &[String]) -> Result<(), Error> {
+    let path = match &ctx.cache {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let inputs = files(ctx, inputs)?;
+    let mut cache = load(path)?;
+    cache.insert(key(ctx, inputs.len(), labels), hash(&inputs)?);
+    let text: String = cache
+        .iter()
+        .map(|(key, hash)| format!("{} {}\n", key, hash))
+        .collect();
+    fs::write(path, text).map_err(|e| Error::io(format!("writing {}", path.display()), e))
+}
+
+// Files matching the input patterns, sorted so that they hash the same way
+// each time.
+fn files(ctx: &Context, patterns: &[String]) -> Result<Vec<PathBuf>, Error> {
+    let mut files = vec![];
+    for pattern in patterns {
+        let paths = ctx
+            .glob(pattern)
+            .map_err(|e| Error::parse(format!("invalid input pattern {}: {}", pattern, e)))?;
+        files.extend(paths.into_iter().map(|path| ctx.path(path)));
+    }
+    files.retain(|path| path.is_file());
+    files.sort();
+    files.dedup();
+    Ok(files)
+}
+
+// Cache entries, keyed by what was run.
+fn load(path: &Path) -> Result<BTreeMap<String, String>, Error> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(Error::io(format!("reading {}", path.display()), e)),
+    };
+    Ok(text
+        .lines()
+        .filter_map(|line| line.split_once(' '))
+        .map(|(key, hash)| (key.to_owned(), hash.to_owned()))
+        .collect())
+}
+
+// Identify items by what they run and where, so that changing either
+// invalidates the cache.
+// The number of inputs is included so that adding or removing one does too.
+fn key(ctx: &Context, inputs: usize, labels: &[String]) -> String {
+    let mut key = format!("{:?}\n{}\n", ctx.dir, inputs);
+    for label in labels {
+        key.push_str(label);
+        key.push('\n');
+    }
+    format!("{:016x}", fnv(key.as_bytes(), FNV_OFFSET))
+}
+
+// Hash the paths and contents of the files.
+fn hash(files: &[PathBuf]) -> Result<String, Error> {
+    let mut hash = FNV_OFFSET;
+    for path in files {
+        let contents =
+            fs::read(path).map_err(|e| Error::io(format!("reading {}", path.display()), e))?;
+        hash = fnv(path.to_string_lossy().as_bytes(), hash);
+        hash = fnv(&contents, hash);
+    }
+    Ok(format!("{:016x}", hash))
+}
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+// FNV-1a, which unlike std's hashers is the same from one build to the next.
+fn fnv(bytes: &[u8], mut hash: u64) -> u64 {
+    for byte in bytes {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::testing::TempDir;
+    use std::time::Duration;
+
+    #[test]
+    fn test_modified() {
+        let dir = TempDir::new("incremental");
+        fs::create_dir_all(dir.join("src")).expect("creating dir");
+        let ctx = Context {
+            dir: Some(dir.clone()),
+            ..Context::default()
+        };
+        let inputs = vec!["src/*.c".to_owned()];
+        let outputs = vec![PathBuf::from("app")];
+        let check = |ctx: &Context| up_to_date(ctx, &inputs, &outputs, &[]).expect("checking");
+        let touch = |name: &str, age: u64| {
+            let file = fs::File::create(dir.join(name)).expect("creating file");
+            file.set_modified(SystemTime::now() - Duration::from_secs(age))
+                .expect("setting modified time");
+        };
+
+        touch("src/main.c", 20);
+        assert!(!check(&ctx), "missing output");
+        touch("app", 10);
+        assert!(check(&ctx), "output newer");
+        assert!(!check(&Context {
+            force: true,
+            ..ctx.clone()
+        }));
+        touch("src/util.c", 0);
+        assert!(!check(&ctx), "input newer");
+    }
+
+    #[test]
+    fn test_hash() {
+        let dir = TempDir::new("hash");
+        let ctx = Context {
+            dir: Some(dir.clone()),
+            cache: Some(dir.join(CACHE_FILE)),
+            ..Context::default()
+        };
+        let inputs = vec!["*.txt".to_owned()];
+        let outputs = vec![PathBuf::from("out")];
+        let check = || up_to_date(&ctx, &inputs, &outputs, &[]).expect("checking");
+        let write = |name: &str, text: &str| fs::write(dir.join(name), text).expect("writing");
+
+        write("in.txt", "one");
+        write("out", "");
+        assert!(!check(), "never recorded");
+        record(&ctx, &inputs, &[]).expect("recording");
+        assert!(check(), "unchanged");
+        write("in.txt", "two");
+        assert!(!check(), "contents changed");
+        write("in.txt", "one");
+        assert!(check(), "changed back");
+    }
+
+    #[test]
+    fn test_fnv() {
+        assert_eq!(fnv(b"", FNV_OFFSET), FNV_OFFSET);
+        assert_eq!(fnv(b"a", FNV_OFFSET), 0xaf63dc4c8601ec8c);
+    }
+}
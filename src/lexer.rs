@@ -0,0 +1,544 @@
+use crate::error::{Error, Span};
+use std::iter::Peekable;
+use std::str::Chars;
+
+// Token is a lexical unit of a run file line.
+// Operators are recognised regardless of surrounding whitespace, unless they
+// are quoted or escaped.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    Word(Word),
+    // "|"
+    Pipe,
+    // ";"
+    Semi,
+    // ">"
+    Great,
+    // ">>"
+    GreatGreat,
+    // "<"
+    Less,
+    // "2>"
+    ErrGreat,
+    // "2>&1"
+    ErrToOut,
+    // "&>"
+    AndGreat,
+    // "&&"
+    AndAnd,
+    // "||"
+    OrOr,
+}
+
+// Word is a single argument, made of literal and variable parts:
+//
+//  v$(Version)".exe"
+//  ^^----------^^^^^
+//  |     |       |
+//  Lit  Var    Quoted
+#[derive(Debug, Clone, PartialEq)]
+pub struct Word {
+    pub parts: Vec<Part>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Part {
+    Lit(String),
+    Quoted(String),
+    // A "$(...)" reference.
+    Var {
+        name: String,
+        fallback: Option<Fallback>,
+        span: Span,
+    },
+}
+
+// What to do instead when a variable has no value.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fallback {
+    // Use the given value, "$(name:-value)".
+    Default(String),
+    // Fail with the given message, "$(name:?message)".
+    Error(String),
+}
+
+impl Word {
+    // Literal text of a word that has no quotes or variables.
+    pub fn bare(&self) -> Option<&str> {
+        match self.parts.as_slice() {
+            [Part::Lit(text)] => Some(text),
+            _ => None,
+        }
+    }
+}
+
+// Characters that end an unquoted word.
+fn is_operator(c: char) -> bool {
+    matches!(c, '|' | ';' | '>' | '<')
+}
+
+// Characters a backslash escapes outside of quotes.
+// Other backslashes are kept as-is, so that Windows paths like "C:\foo" work
+// without doubling up.
+fn is_escapable(c: char) -> bool {
+    is_operator(c) || c.is_whitespace() || matches!(c, '"' | '\'' | '$' | '&')
+}
+
+// Lex splits a line into tokens.
+// `number` is the line number, used for spans.
+pub fn lex(line: &str, number: usize) -> Result<Vec<(Token, Span)>, Error> {
+    Lexer {
+        src: line.chars().peekable(),
+        line: number,
+        col: 1,
+    }
+    .collect()
+}
+
+struct Lexer<'a> {
+    src: Peekable<Chars<'a>>,
+    line: usize,
+    // Column of the next char in src.
+    col: usize,
+}
+
+impl<'a> Lexer<'a> {
+    fn next_char(&mut self) -> Option<char> {
+        let c = self.src.next()?;
+        self.col += 1;
+        Some(c)
+    }
+
+    // Whether the source continues with `s`.
+    fn ahead(&self, s: &str) -> bool {
+        self.src.clone().take(s.chars().count()).eq(s.chars())
+    }
+
+    fn span(&self, start: usize) -> Span {
+        Span {
+            line: self.line,
+            col: start,
+            len: self.col - start,
+        }
+    }
+
+    fn word(&mut self) -> Result<Word, Error> {
+        let start = self.col;
+        let mut parts = vec![];
+        let mut lit = String::new();
+
+        while let Some(&c) = self.src.peek() {
+            if c.is_whitespace() || is_operator(c) || self.ahead("&>") || self.ahead("&&") {
+                break;
+            }
+            match c {
+                '"' | '\'' => {
+                    if !lit.is_empty() {
+                        parts.push(Part::Lit(std::mem::take(&mut lit)));
+                    }
+                    self.quoted(&mut parts)?;
+                }
+                '$' => {
+                    let var_start = self.col;
+                    self.next_char();
+                    if self.src.peek() == Some(&'(') {
+                        if !lit.is_empty() {
+                            parts.push(Part::Lit(std::mem::take(&mut lit)));
+                        }
+                        parts.push(self.var(var_start)?);
+                    } else {
+                        lit.push('$');
+                    }
+                }
+                '\\' => {
+                    self.next_char();
+                    match self.src.peek() {
+                        Some(&c) if is_escapable(c) => {
+                            self.next_char();
+                            lit.push(c);
+                        }
+                        _ => lit.push('\\'),
+                    }
+                }
+                c => {
+                    self.next_char();
+                    lit.push(c);
+                }
+            }
+        }
+
+        if !lit.is_empty() {
+            parts.push(Part::Lit(lit));
+        }
+        Ok(Word {
+            parts,
+            span: self.span(start),
+        })
+    }
+
+    // Lex a quoted string, starting at the opening quote.
+    // Double quotes allow variables and escaping of '"', '$' and '\'.
+    // Single quotes are entirely literal.
+    fn quoted(&mut self, parts: &mut Vec<Part>) -> Result<(), Error> {
+        let start = self.col;
+        let quote = self.next_char().expect("opening quote");
+        let mut text = String::new();
+
+        loop {
+            match self.next_char() {
+                None => {
+                    return Err(Error::parse("unterminated quote").at(self.span(start)));
+                }
+                Some(c) if c == quote => break,
+                Some('\\') if quote == '"' => match self.src.peek() {
+                    Some(&c) if matches!(c, '"' | '$' | '\\') => {
+                        self.next_char();
+                        text.push(c);
+                    }
+                    _ => text.push('\\'),
+                },
+                Some('$') if quote == '"' && self.src.peek() == Some(&'(') => {
+                    if !text.is_empty() {
+                        parts.push(Part::Quoted(std::mem::take(&mut text)));
+                    }
+                    parts.push(self.var(self.col - 1)?);
+                }
+                Some(c) => text.push(c),
+            }
+        }
+
+        // Keep empty strings, eg `""`, as an (empty) argument.
+        if !text.is_empty() || parts.is_empty() {
+            parts.push(Part::Quoted(text));
+        }
+        Ok(())
+    }
+
+    // Lex a variable reference, starting at the "(" following a "$".
+    // Parentheses may nest, eg "$(msg:-(none))".
+    fn var(&mut self, start: usize) -> Result<Part, Error> {
+        self.next_char();
+        let mut name = String::new();
+        let mut depth = 0;
+
+        loop {
+            match self.next_char() {
+                None => {
+                    return Err(Error::parse("unterminated variable").at(self.span(start)));
+                }
+                Some(')') if depth == 0 => break,
+                Some(c) => {
+                    match c {
+                        '(' => depth += 1,
+                        ')' => depth -= 1,
+                        _ => {}
+                    };
+                    name.push(c);
+                }
+            }
+        }
+
+        // Whichever of ":-" or ":?" comes first introduces the fallback, so
+        // that either may appear in the fallback itself.
+        let fallback = match name.find(":-").into_iter().chain(name.find(":?")).min() {
+            Some(at) => {
+                let rest = name.split_off(at);
+                let value = rest[2..].to_owned();
+                Some(if rest.starts_with(":-") {
+                    Fallback::Default(value)
+                } else {
+                    Fallback::Error(value)
+                })
+            }
+            None => None,
+        };
+        Ok(Part::Var {
+            name,
+            fallback,
+            span: self.span(start),
+        })
+    }
+}
+
+impl<'a> Iterator for Lexer<'a> {
+    type Item = Result<(Token, Span), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.src.peek()?.is_whitespace() {
+            self.next_char();
+        }
+
+        let start = self.col;
+        // Longest operators first, so that eg "2>&1" isn't lexed as "2>".
+        // "2>" is only an operator at the start of a word, "a2>b" is "a2" ">" "b".
+        let operators = [
+            ("2>&1", Token::ErrToOut),
+            ("2>", Token::ErrGreat),
+            ("&>", Token::AndGreat),
+            ("&&", Token::AndAnd),
+            ("||", Token::OrOr),
+            ("|", Token::Pipe),
+            (";", Token::Semi),
+            (">>", Token::GreatGreat),
+            (">", Token::Great),
+            ("<", Token::Less),
+        ];
+        let token = match operators.iter().find(|(op, _)| self.ahead(op)) {
+            Some((op, token)) => {
+                for _ in op.chars() {
+                    self.next_char();
+                }
+                token.clone()
+            }
+            None => {
+                return Some(
+                    self.word()
+                        .map(|word| (Token::Word(word), self.span(start))),
+                );
+            }
+        };
+        Some(Ok((token, self.span(start))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn span(col: usize, len: usize) -> Span {
+        Span { line: 1, col, len }
+    }
+
+    fn tokens(line: &str) -> Vec<Token> {
+        lex(line, 1)
+            .expect("lexing")
+            .into_iter()
+            .map(|(token, _)| token)
+            .collect()
+    }
+
+    fn lit(text: &str, col: usize) -> Token {
+        Token::Word(Word {
+            parts: vec![Part::Lit(text.into())],
+            span: span(col, text.chars().count()),
+        })
+    }
+
+    #[test]
+    fn test_operators_without_whitespace() {
+        assert_eq!(
+            tokens("a|b;c>d"),
+            vec![
+                lit("a", 1),
+                Token::Pipe,
+                lit("b", 3),
+                Token::Semi,
+                lit("c", 5),
+                Token::Great,
+                lit("d", 7),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_stderr_operators() {
+        let got = lex("a 2>err 2>&1&>all b2>c", 1)
+            .expect("lexing")
+            .into_iter()
+            .map(|(token, span)| (token, span.col))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            got,
+            vec![
+                (lit("a", 1), 1),
+                (Token::ErrGreat, 3),
+                (lit("err", 5), 5),
+                (Token::ErrToOut, 9),
+                (Token::AndGreat, 13),
+                (lit("all", 15), 15),
+                (lit("b2", 19), 19),
+                (Token::Great, 21),
+                (lit("c", 22), 22),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_file_operators() {
+        assert_eq!(
+            tokens("a<in>>out b > c"),
+            vec![
+                lit("a", 1),
+                Token::Less,
+                lit("in", 3),
+                Token::GreatGreat,
+                lit("out", 7),
+                lit("b", 11),
+                Token::Great,
+                lit("c", 15),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_chain_operators() {
+        assert_eq!(
+            tokens("a&&b || c|d & e"),
+            vec![
+                lit("a", 1),
+                Token::AndAnd,
+                lit("b", 4),
+                Token::OrOr,
+                lit("c", 9),
+                Token::Pipe,
+                lit("d", 11),
+                lit("&", 13),
+                lit("e", 15),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_quoted_operators() {
+        assert_eq!(
+            tokens(r#"rg " | " 'a;b'"#),
+            vec![
+                lit("rg", 1),
+                Token::Word(Word {
+                    parts: vec![Part::Quoted(" | ".into())],
+                    span: span(4, 5),
+                }),
+                Token::Word(Word {
+                    parts: vec![Part::Quoted("a;b".into())],
+                    span: span(10, 5),
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_escapes() {
+        assert_eq!(
+            tokens(r#"a\|b C:\dir\file "say \"hi\"" \$x"#),
+            vec![
+                Token::Word(Word {
+                    parts: vec![Part::Lit("a|b".into())],
+                    span: span(1, 4),
+                }),
+                lit(r"C:\dir\file", 6),
+                Token::Word(Word {
+                    parts: vec![Part::Quoted(r#"say "hi""#.into())],
+                    span: span(18, 12),
+                }),
+                Token::Word(Word {
+                    parts: vec![Part::Lit("$x".into())],
+                    span: span(31, 3),
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_variables() {
+        assert_eq!(
+            tokens(r#"v$(Version)".exe" "$(a) $(b)""#),
+            vec![
+                Token::Word(Word {
+                    parts: vec![
+                        Part::Lit("v".into()),
+                        Part::Var {
+                            name: "Version".into(),
+                            fallback: None,
+                            span: span(2, 10),
+                        },
+                        Part::Quoted(".exe".into()),
+                    ],
+                    span: span(1, 17),
+                }),
+                Token::Word(Word {
+                    parts: vec![
+                        Part::Var {
+                            name: "a".into(),
+                            fallback: None,
+                            span: span(20, 4),
+                        },
+                        Part::Quoted(" ".into()),
+                        Part::Var {
+                            name: "b".into(),
+                            fallback: None,
+                            span: span(25, 4),
+                        },
+                    ],
+                    span: span(19, 11),
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fallbacks() {
+        let fallbacks = |line| -> Vec<(String, Option<Fallback>)> {
+            lex(line, 1)
+                .expect(line)
+                .into_iter()
+                .flat_map(|(token, _)| match token {
+                    Token::Word(word) => word.parts,
+                    _ => vec![],
+                })
+                .filter_map(|part| match part {
+                    Part::Var { name, fallback, .. } => Some((name, fallback)),
+                    _ => None,
+                })
+                .collect()
+        };
+        assert_eq!(
+            fallbacks("$(cc:-gcc) $(msg:-(none)) $(target:?must pass -target) $(env:HOME)"),
+            vec![
+                ("cc".into(), Some(Fallback::Default("gcc".into()))),
+                ("msg".into(), Some(Fallback::Default("(none)".into()))),
+                (
+                    "target".into(),
+                    Some(Fallback::Error("must pass -target".into()))
+                ),
+                ("env:HOME".into(), None),
+            ]
+        );
+        assert_eq!(
+            fallbacks("$(a:?b:-c) $(d:-)"),
+            vec![
+                ("a".into(), Some(Fallback::Error("b:-c".into()))),
+                ("d".into(), Some(Fallback::Default("".into()))),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_empty_quotes() {
+        assert_eq!(
+            tokens(r#"echo """#),
+            vec![
+                lit("echo", 1),
+                Token::Word(Word {
+                    parts: vec![Part::Quoted("".into())],
+                    span: span(6, 2),
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unterminated() {
+        let err = lex(r#"echo "abc"#, 3).expect_err("unterminated quote");
+        assert_eq!(
+            err.span,
+            Some(Span {
+                line: 3,
+                col: 6,
+                len: 4,
+            })
+        );
+        assert!(lex("echo $(abc", 1).is_err());
+    }
+}
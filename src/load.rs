@@ -0,0 +1,179 @@
+// Loading turns the tasks of a run file into items, loading any files they
+// include along the way.
+//
+// Included files are loaded up front, like the rest of the tasks are parsed,
+// so that mistakes anywhere are reported before anything runs. Errors are
+// attributed to the file they occur in.
+
+use crate::env::Environment;
+use crate::error::Error;
+use crate::parser::{Include, Item, ItemParser};
+use crate::task::{RunFile, Task};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Parse the tasks of the run file `file`, with text `text`, against `env`.
+// `stack` holds the files being loaded, outermost first, so that a file
+// including itself, however indirectly, can be caught.
+pub fn items(
+    file: &str,
+    text: &str,
+    plan: &[&Task],
+    env: &Environment,
+    stack: &mut Vec<PathBuf>,
+) -> Result<Vec<Item>, Error> {
+    let parser = ItemParser { env };
+    let mut items = vec![];
+    let mut errors = vec![];
+    for task in plan {
+        match parser.parse_at(&task.body, task.line) {
+            Ok(task_items) => items.extend(task_items),
+            Err(err) => errors.push(err),
+        }
+    }
+    for item in &mut items {
+        if let Item::Include(include) = item {
+            if let Err(err) = load(file, include, stack) {
+                errors.push(err);
+            }
+        }
+    }
+    Error::all(errors).map_err(|err| err.with_source(file, text))?;
+    Ok(items)
+}
+
+// The path a run file is identified by when checking for cycles.
+pub fn identity(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_owned())
+}
+
+// Load the file `include` refers to, relative to the file `from`.
+fn load(from: &str, include: &mut Include, stack: &mut Vec<PathBuf>) -> Result<(), Error> {
+    let span = include.span;
+    let path = Path::new(from)
+        .parent()
+        .unwrap_or_else(|| Path::new(""))
+        .join(&include.path);
+    let file = path.display().to_string();
+    let text = fs::read_to_string(&path)
+        .map_err(|e| Error::io(format!("including {}", file), e).at(span))?;
+
+    let id = identity(&path);
+    if stack.contains(&id) {
+        let names: Vec<String> = stack
+            .iter()
+            .chain(Some(&id))
+            .map(|path| path.display().to_string())
+            .collect();
+        return Err(Error::parse(format!("include cycle: {}", names.join(" -> "))).at(span));
+    }
+
+    let located = |err: Error| err.with_source(&file, &text);
+    let run_file = RunFile::parse(&text).map_err(located)?;
+    let plan = run_file.plan(None).map_err(located)?;
+    // Argument errors are the including file's, so point at the include.
+    let env = Environment::from_args(include.args.iter().cloned())
+        .and_then(|mut env| env.validate(&run_file.params).map(|_| env))
+        .map_err(|err| Error::parse(format!("including {}: {}", file, err)).at(span))?;
+
+    stack.push(id);
+    let items = items(&file, &text, &plan, &env, stack);
+    stack.pop();
+    include.items = items?;
+    include.file = file;
+    include.text = text;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::testing::TempDir;
+    use pretty_assertions::assert_eq;
+
+    // Write `files` into a fresh directory, then load "main.run" from it.
+    fn load_files(name: &str, files: &[(&str, &str)]) -> Result<Vec<Item>, Error> {
+        let dir = TempDir::new(&format!("load-{}", name));
+        fs::create_dir_all(dir.join("lib")).expect("creating dir");
+        for (file, text) in files {
+            fs::write(dir.join(file), text).expect("writing run file");
+        }
+        let path = dir.join("main.run");
+        let file = path.display().to_string();
+        let text = fs::read_to_string(&path).expect("reading run file");
+        let run_file = RunFile::parse(&text)?;
+        let plan = run_file.plan(None)?;
+        items(
+            &file,
+            &text,
+            &plan,
+            &Environment::default(),
+            &mut vec![identity(&path)],
+        )
+    }
+
+    #[test]
+    fn test_include() {
+        let items = load_files(
+            "ok",
+            &[
+                ("main.run", "include lib/common.run -who there\necho main\n"),
+                (
+                    "lib/common.run",
+                    "param who\ninclude more.run\necho hi $(who)\n",
+                ),
+                ("lib/more.run", "echo more\n"),
+            ],
+        )
+        .expect("loading");
+        let include = match items.as_slice() {
+            [Item::Include(include), Item::Pipeline { .. }] => include,
+            items => panic!("unexpected items: {:?}", items),
+        };
+        assert!(include.file.ends_with("common.run"), "{}", include.file);
+        match include.items.as_slice() {
+            [Item::Include(more), Item::Pipeline { cmds, .. }] => {
+                assert_eq!(cmds[0].to_string(), "echo hi there");
+                assert_eq!(more.items.len(), 1);
+            }
+            items => panic!("unexpected items: {:?}", items),
+        }
+    }
+
+    #[test]
+    fn test_include_errors() {
+        let err = load_files(
+            "cycle",
+            &[
+                ("main.run", "echo main\ninclude lib/a.run\n"),
+                ("lib/a.run", "include ../main.run\n"),
+            ],
+        )
+        .expect_err("cycle");
+        let report = err.report();
+        assert!(report.starts_with("error: include cycle: "), "{}", report);
+        assert!(report.contains("a.run:1:1"), "{}", report);
+
+        let err = load_files(
+            "args",
+            &[
+                ("main.run", "\ninclude lib/a.run\n"),
+                ("lib/a.run", "param who\necho $(who)\n"),
+            ],
+        )
+        .expect_err("missing argument");
+        let report = err.report();
+        assert!(report.contains("missing parameter: -who"), "{}", report);
+        assert!(report.contains("main.run:2:1"), "{}", report);
+
+        let err = load_files(
+            "parse",
+            &[
+                ("main.run", "include lib/a.run\n"),
+                ("lib/a.run", "echo ok\necho $(missing)\n"),
+            ],
+        )
+        .expect_err("missing variable");
+        assert!(err.report().contains("a.run:2:6"), "{}", err.report());
+    }
+}
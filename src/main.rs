@@ -2,107 +2,341 @@
 //! Designed to be portable and simple for the 80% case: running a
 //! command with arguments and combining commands through pipes.
 //!
-//! Note: no pipe redirection, no failure handling, nothing fancy.
+//! Note: redirection is limited to files (`>`, `>>`, `<`, `2>`) and merging
+//! stderr into stdout (`2>&1`, `&>`), nothing fancy. A failing command stops
+//! the run and its exit code becomes the exit code of `run`.
 //! Will add features as I need them in my workflow, rather than trying to
 //! support the universe.
 //!
 //! TODO:
 //! - Verbosity flag.
 //! - Colorize comments and command literals.
-//! - Graceful errors (no panic!), panicking is bad user experience.
 //! - Support Serde on top of "custom" format?
-//! - Shell interface (basically, a loop with a prompt).
 //!
 
+mod ast;
+mod builtin;
 mod config;
+mod discover;
 mod env;
+mod error;
+mod incremental;
+mod lexer;
+mod load;
+mod params;
 mod parser;
 mod pipeline;
+mod shell;
+mod task;
 mod util;
+mod watch;
 
 use config::Config;
 use env::Environment;
-use parser::{Item, ItemParser};
-use pipeline::Pipeline;
-use std;
+use error::Error;
+use parser::{Chdir, Item, Stderr};
+use pipeline::Context;
+use shell::Shell;
 use std::fs::File;
 use std::io::prelude::*;
+use std::iter::Peekable;
+use std::path::Path;
+use task::RunFile;
 
 fn main() {
-    // TODO(jfm): Handle multiple ".run" files.
-    // Do we want to execute them all? Probably not? Should there be more than
-    // one? Not sure. TBD.
+    if let Err(err) = run() {
+        eprintln!("{}", err.report());
+        // Exit with the failing command's code so that callers (eg CI) can
+        // rely on it.
+        std::process::exit(err.code());
+    }
+}
 
-    let mut file: String = String::new();
+fn run() -> Result<(), Error> {
     let mut args = std::env::args().skip(1).peekable();
 
-    if let Some(mut run_file) = args.next() {
-        if !run_file.ends_with(".run") {
-            run_file.push_str(".run");
+    match args.peek().map(String::as_str) {
+        Some("--shell") => {
+            args.next();
+            return shell(args);
         }
-        File::open(&run_file)
-            .map_err(|e| format!("opening {}: {}", &run_file, e))
-            .unwrap()
-            .read_to_string(&mut file)
-            .expect("reading run file");
+        Some("--list") => return list(),
+        _ => {}
     }
+    // Flags may come before the run file too, eg "run --watch build.run".
+    let mut config = Config::from_args(&mut args)?;
+
+    // The run file is the first argument if it names one, otherwise it's
+    // discovered, in which case the argument may name a task.
+    let given = match args.peek() {
+        Some(arg) if arg.ends_with(".run") || discover::is_runfile(Path::new(arg)) => {
+            Some(arg.clone())
+        }
+        Some(arg) if Path::new(&format!("{}.run", arg)).is_file() => Some(format!("{}.run", arg)),
+        _ => None,
+    };
+    let path = match given {
+        Some(path) => {
+            args.next();
+            path
+        }
+        None => find(
+            args.peek()
+                .map(String::as_str)
+                .filter(|arg| !arg.starts_with('-')),
+        )?,
+    };
+    let mut file = String::new();
+    File::open(&path)
+        .and_then(|mut f| f.read_to_string(&mut file))
+        .map_err(|e| Error::io(format!("reading {}", &path), e))?;
+
+    // Errors that point into the run file are reported against it.
+    let located = |err: Error| err.with_source(&path, &file);
+
+    let run_file = RunFile::parse(&file).map_err(located)?;
 
     // Consume any config flags we care about.
-    let config = Config::from_args(&mut args);
-
-    // Wrap each unique argument in quotes for the environment parser.
-    // Quotes get stripped on entry, so we add them back.
-    // #perf
-    let s: String = args.fold(String::new(), |mut buf, next| {
-        buf.push('"');
-        buf.extend(next.chars());
-        buf.push('"');
-        buf.push(' ');
-        buf
-    });
-
-    let environment: Environment = s
-        .parse()
-        .map_err(|e| format!("parsing environment: {}", e))
-        .unwrap();
-
-    let items = ItemParser { env: &environment }
-        .parse(&file)
-        .map_err(|e| format!("parsing commands: {}", e))
-        .unwrap();
-
-    if config.dry_run {
-        for item in items {
-            match item {
-                Item::Comment(comment) => {
-                    println!("{}", comment);
-                }
-                Item::Pipeline { cmds, terminus, .. } => {
-                    for cmd in cmds {
-                        println!("{}", &cmd);
-                    }
-                    if let Some(terminus) = terminus {
-                        println!("> {}", &terminus.to_string_lossy());
-                    }
-                }
-            };
+    config.update_from_args(&mut args)?;
+
+    // A leading argument that names a task selects it, otherwise the default
+    // task runs and the argument is left for the environment. Files with
+    // named tasks take no leading positional argument unless they declare
+    // one, so anything else there is a mistaken task name.
+    let name = match args.peek() {
+        Some(arg) if run_file.task(Some(arg)).is_some() => args.next(),
+        Some(arg) if !arg.starts_with('-') && expects_task(&run_file) => {
+            let tasks: Vec<&str> = run_file.tasks.iter().map(|t| t.name.as_str()).collect();
+            return Err(Error::parse(format!(
+                "no such task: {} (tasks: {})",
+                arg,
+                tasks.join(", ")
+            )));
+        }
+        _ => None,
+    };
+
+    // Config flags may also come after the task and among its arguments.
+    let rest = config.update_from_mixed_args(&mut args)?;
+
+    if config.list {
+        println!("{}", run_file.list(&path));
+        return Ok(());
+    }
+
+    if config.help {
+        let tasks: Vec<&str> = run_file.tasks.iter().map(|t| t.name.as_str()).collect();
+        println!("{}", params::usage(&path, &tasks, &run_file.params));
+        return Ok(());
+    }
+
+    let environment = Environment::from_args(rest.into_iter())?;
+    let (file, items) = load_items(&path, name.as_deref(), &environment)?;
+    let located = |err: Error| err.with_source(&path, &file);
+
+    // Directory changes are tracked by dry runs too, so that each shows the
+    // directory later items would run in.
+    let mut ctx = config.context(Some(Path::new(&path)));
+    if config.watch && !config.dry_run {
+        return watch::watch(&ctx, &path, &config.watch_globs, || {
+            load_items(&path, name.as_deref(), &environment)
+        });
+    } else if config.dry_run {
+        for item in &items {
+            dry_run(item, &mut ctx).map_err(located)?;
         }
     } else {
-        for item in items {
-            match item {
-                Item::Comment(comment) => {
-                    println!("{}", comment);
+        ctx.run(items, &located)?;
+    }
+
+    Ok(())
+}
+
+// Whether a run file's leading argument can only be a task: it has named
+// tasks and declares no positional parameters.
+fn expects_task(run_file: &RunFile) -> bool {
+    run_file.tasks.iter().any(|t| t.name != task::DEFAULT_TASK)
+        && !run_file.params.iter().any(|p| p.position().is_some())
+}
+
+// Read the run file at `path` and parse the items of `task`, returning the
+// file's text along with them. Every task is parsed up front so that mistakes,
+// in any task or included file, are reported together before anything runs.
+fn load_items(
+    path: &str,
+    task: Option<&str>,
+    args: &Environment,
+) -> Result<(String, Vec<Item>), Error> {
+    let file =
+        std::fs::read_to_string(path).map_err(|e| Error::io(format!("reading {}", path), e))?;
+    let located = |err: Error| err.with_source(path, &file);
+    let run_file = RunFile::parse(&file).map_err(located)?;
+    let plan = run_file.plan(task).map_err(located)?;
+
+    let mut environment = args.clone();
+    environment.validate(&run_file.params)?;
+
+    let mut stack = vec![load::identity(Path::new(path))];
+    let items = load::items(path, &file, &plan, &environment, &mut stack)?;
+    Ok((file, items))
+}
+
+// List the run files that could be discovered, along with their tasks.
+fn list() -> Result<(), Error> {
+    let cwd = std::env::current_dir().map_err(|e| Error::io("reading current directory", e))?;
+    let (dir, files) = discover::nearest(&cwd)
+        .ok_or_else(|| Error::parse(format!("no run files in {} or above", cwd.display())))?;
+    if dir != cwd {
+        println!("// in {}", dir.display());
+    }
+    let mut listings = vec![];
+    for path in files {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let listing = std::fs::read_to_string(&path)
+            .map_err(|e| Error::io(format!("reading {}", name), e))
+            .and_then(|text| RunFile::parse(&text))
+            .map(|run_file| run_file.list(&name))
+            .unwrap_or_else(|err| format!("{}  error: {}", name, err));
+        listings.push(listing);
+    }
+    println!("{}", listings.join("\n\n"));
+    Ok(())
+}
+
+// Find the run file to use, and switch to its directory like "make -C".
+fn find(task: Option<&str>) -> Result<String, Error> {
+    let cwd = std::env::current_dir().map_err(|e| Error::io("reading current directory", e))?;
+    let path = discover::find(&cwd, task)?;
+    let (dir, name) = match (path.parent(), path.file_name()) {
+        (Some(dir), Some(name)) => (dir, name.to_string_lossy().into_owned()),
+        _ => return Err(Error::parse(format!("not a run file: {}", path.display()))),
+    };
+    if dir != cwd {
+        eprintln!("run: entering directory {}", dir.display());
+        std::env::set_current_dir(dir)
+            .map_err(|e| Error::io(format!("entering {}", dir.display()), e))?;
+    }
+    Ok(name)
+}
+
+// Open an interactive prompt, running the run file first if one is given.
+fn shell(mut args: Peekable<impl Iterator<Item = String>>) -> Result<(), Error> {
+    let file = args.next_if(|arg| !arg.starts_with('-'));
+    let config = Config::from_args(&mut args)?;
+    let ctx = config.context(file.as_deref().map(Path::new));
+    let mut shell = Shell::new(
+        Environment::from_args(args)?,
+        ctx,
+        Shell::default_history_file(),
+    );
+    if let Some(file) = file {
+        if let Err(err) = shell.load(&file, None) {
+            eprintln!("{}", err.report());
+        }
+    }
+    shell.repl(std::io::stdin().lock(), std::io::stdout())
+}
+
+// Print what an item would execute, without executing it.
+fn dry_run(item: &Item, ctx: &mut Context) -> Result<(), Error> {
+    match item {
+        Item::Comment(comment) => {
+            println!("{}", comment);
+        }
+        Item::Export(vars) => {
+            let vars: Vec<_> = vars.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+            println!("export {}", vars.join(" "));
+        }
+        Item::Chdir(change, span) => {
+            match change {
+                Chdir::Cd(dir) => println!("cd {}", dir.display()),
+                Chdir::Pushd(dir) => println!("pushd {}", dir.display()),
+                Chdir::Popd => println!("popd"),
+            }
+            ctx.chdir(change, false).map_err(|err| err.at(*span))?;
+            let dir = ctx.dir.clone().unwrap_or_else(|| ".".into());
+            println!("// in {}", dir.display());
+        }
+        Item::Incremental {
+            inputs,
+            outputs,
+            items,
+        } => {
+            let labels: Vec<String> = items.iter().filter_map(Item::label).collect();
+            if incremental::up_to_date(ctx, inputs, outputs, &labels)? {
+                println!("// up to date, would skip: {}", labels.join("; "));
+            } else {
+                for item in items {
+                    dry_run(item, ctx)?;
                 }
-                Item::Pipeline { ignore_failure, .. } => {
-                    if let Err(err) = item.execute(std::io::stdout()) {
-                        println!("error: {}", err);
-
-                        if !ignore_failure {
-                            break;
-                        }
-                    }
+            }
+        }
+        Item::Inputs(_) | Item::Outputs(_) => {}
+        Item::Let { name, pipeline } => {
+            print!("let {} = ", name);
+            dry_run(pipeline, ctx)?;
+            // Nothing's captured, so later lines show where it would go.
+            ctx.captured.insert(name.clone(), format!("$({})", name));
+        }
+        Item::Deferred(deferred) => {
+            for item in deferred.resolve(&ctx.captured)? {
+                dry_run(&item, ctx)?;
+            }
+        }
+        Item::Include(include) => {
+            println!("// include {}", include.file);
+            for item in &include.items {
+                dry_run(item, ctx)?;
+            }
+        }
+        Item::Pipeline {
+            cmds,
+            terminus,
+            append,
+            stderr,
+            stdin,
+            timeout,
+            retry,
+            ..
+        } => {
+            if let Some(timeout) = timeout {
+                println!("timeout {:?}", timeout);
+            }
+            if let Some(retry) = retry {
+                match retry.backoff.is_zero() {
+                    true => println!("retry {}", retry.attempts),
+                    false => println!("retry {} {:?}", retry.attempts, retry.backoff),
                 }
             }
+            if let Some(stdin) = stdin {
+                println!("< {}", &stdin.to_string_lossy());
+            }
+            for cmd in cmds {
+                println!("{}", &cmd);
+            }
+            if let Some(terminus) = terminus {
+                let op = if *append { ">>" } else { ">" };
+                println!("{} {}", op, &terminus.to_string_lossy());
+            }
+            match stderr {
+                Some(Stderr::File(path)) => println!("2> {}", &path.to_string_lossy()),
+                Some(Stderr::Stdout) => println!("2>&1"),
+                None => {}
+            }
+        }
+        Item::Parallel(items) => {
+            for item in items {
+                print!("& ");
+                dry_run(item, ctx)?;
+            }
+        }
+        Item::Chain { first, rest } => {
+            dry_run(first, ctx)?;
+            for (op, item) in rest {
+                print!("{} ", op);
+                dry_run(item, ctx)?;
+            }
         }
     }
+    Ok(())
 }
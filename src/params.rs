@@ -0,0 +1,288 @@
+// Parameters a run file declares, so that arguments can be checked before
+// anything runs and usage can be generated for "--help".
+//
+// Declarations are unindented lines before the first task, described by the
+// comment directly above them:
+//
+//  // C compiler to use.
+//  param cc: string = gcc
+//  // Build profile.
+//  param profile: enum(debug, release) = debug
+//  param 1: path
+//
+// Named parameters are passed as "-name value", numbered parameters are
+// positional. Without a type a parameter is a string, without a default it is
+// required.
+
+use crate::error::{Error, Span};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Param {
+    pub name: String,
+    pub kind: Kind,
+    pub default: Option<String>,
+    pub description: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Kind {
+    String,
+    Int,
+    Bool,
+    Path,
+    // One of the listed values.
+    Enum(Vec<String>),
+}
+
+impl Param {
+    // Parse a declaration, returning None if the line isn't one.
+    pub fn parse(line: &str, number: usize) -> Option<Result<Param, Error>> {
+        let decl = line.strip_prefix("param ")?;
+        Some(Param::declaration(line, number, decl))
+    }
+
+    fn declaration(line: &str, number: usize, decl: &str) -> Result<Param, Error> {
+        let (decl, default) = match decl.split_once('=') {
+            Some((decl, default)) => (decl, Some(default.trim())),
+            None => (decl, None),
+        };
+        let (name, kind) = match decl.split_once(':') {
+            Some((name, kind)) => (name.trim(), kind.trim()),
+            None => (decl.trim(), "string"),
+        };
+
+        let valid = |c: char| c.is_alphanumeric() || c == '_' || c == '-';
+        if name.is_empty() || !name.chars().all(valid) {
+            return Err(Error::parse("expected a parameter name").at(Span::of(number, line, name)));
+        }
+        if name.parse::<usize>() == Ok(0) {
+            return Err(
+                Error::parse("positional parameters start at 1").at(Span::of(number, line, name))
+            );
+        }
+        let kind = Kind::parse(kind).ok_or_else(|| {
+            Error::parse(format!("unknown parameter type: {}", kind))
+                .at(Span::of(number, line, kind))
+        })?;
+
+        let mut param = Param {
+            name: name.into(),
+            kind,
+            default: None,
+            description: None,
+        };
+        if let Some(default) = default {
+            param
+                .check(unquote(default))
+                .map_err(|err| err.at(Span::of(number, line, default)))?;
+            param.default = Some(unquote(default).into());
+        }
+        Ok(param)
+    }
+
+    // Positional index (1-based) of a numbered parameter.
+    pub fn position(&self) -> Option<usize> {
+        self.name.parse().ok()
+    }
+
+    // Check that `value` is valid for the parameter's type.
+    pub fn check(&self, value: &str) -> Result<(), Error> {
+        let valid = match &self.kind {
+            Kind::String => true,
+            Kind::Int => value.parse::<i64>().is_ok(),
+            Kind::Bool => matches!(value, "true" | "false"),
+            Kind::Path => !value.is_empty(),
+            Kind::Enum(choices) => choices.iter().any(|c| c == value),
+        };
+        if valid {
+            Ok(())
+        } else {
+            Err(Error::parse(format!(
+                "{}: expected {}, got {:?}",
+                self.flag(),
+                self.kind,
+                value
+            )))
+        }
+    }
+
+    // How the parameter is passed on the command line.
+    pub fn flag(&self) -> String {
+        match self.position() {
+            Some(_) => format!("<{}>", self.name),
+            None => format!("-{}", self.name),
+        }
+    }
+}
+
+fn unquote(s: &str) -> &str {
+    s.strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .unwrap_or(s)
+}
+
+impl Kind {
+    fn parse(s: &str) -> Option<Kind> {
+        match s {
+            "string" => Some(Kind::String),
+            "int" => Some(Kind::Int),
+            "bool" => Some(Kind::Bool),
+            "path" => Some(Kind::Path),
+            _ => {
+                let choices = s.strip_prefix("enum(")?.strip_suffix(')')?;
+                let choices: Vec<String> = choices
+                    .split(',')
+                    .map(|c| c.trim().to_owned())
+                    .filter(|c| !c.is_empty())
+                    .collect();
+                if choices.is_empty() {
+                    None
+                } else {
+                    Some(Kind::Enum(choices))
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Kind::String => write!(f, "string"),
+            Kind::Int => write!(f, "int"),
+            Kind::Bool => write!(f, "bool"),
+            Kind::Path => write!(f, "path"),
+            Kind::Enum(choices) => write!(f, "{}", choices.join("|")),
+        }
+    }
+}
+
+// Usage generated from the tasks and parameters of the run file at `path`:
+//
+//  usage: run build.run [-cc <string>] <1>
+//
+//  parameters:
+//    -cc <string>  C compiler to use.  (default: gcc)
+//    <1> <path>
+pub fn usage(path: &str, tasks: &[&str], params: &[Param]) -> String {
+    let mut usage = format!("usage: run {}", path);
+    if tasks.len() > 1 {
+        usage.push_str(" [task]");
+    }
+    for param in params {
+        let flag = match param.position() {
+            Some(_) => param.flag(),
+            None => format!("{} <{}>", param.flag(), param.kind),
+        };
+        if param.default.is_some() {
+            usage.push_str(&format!(" [{}]", flag));
+        } else {
+            usage.push_str(&format!(" {}", flag));
+        }
+    }
+
+    if tasks.len() > 1 {
+        usage.push_str(&format!("\n\ntasks: {}", tasks.join(", ")));
+    }
+
+    if !params.is_empty() {
+        usage.push_str("\n\nparameters:");
+        let flags: Vec<String> = params
+            .iter()
+            .map(|param| format!("{} <{}>", param.flag(), param.kind))
+            .collect();
+        let width = flags.iter().map(|f| f.chars().count()).max().unwrap_or(0);
+        for (param, flag) in params.iter().zip(flags) {
+            let mut line = format!("\n  {:width$}", flag, width = width);
+            if let Some(description) = &param.description {
+                line.push_str("  ");
+                line.push_str(description);
+            }
+            if let Some(default) = &param.default {
+                line.push_str(&format!("  (default: {})", default));
+            }
+            usage.push_str(line.trim_end());
+        }
+    }
+    usage
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn param(line: &str) -> Param {
+        Param::parse(line, 1).expect("declaration").expect(line)
+    }
+
+    #[test]
+    fn test_declarations() {
+        assert_eq!(
+            param("param cc"),
+            Param {
+                name: "cc".into(),
+                kind: Kind::String,
+                default: None,
+                description: None,
+            }
+        );
+        assert_eq!(
+            param(r#"param profile: enum(debug, release) = "release""#),
+            Param {
+                name: "profile".into(),
+                kind: Kind::Enum(vec!["debug".into(), "release".into()]),
+                default: Some("release".into()),
+                description: None,
+            }
+        );
+        assert_eq!(param("param 1: path").position(), Some(1));
+        assert_eq!(param("param jobs: int = 4").default, Some("4".into()));
+        assert!(Param::parse("cargo build", 1).is_none());
+    }
+
+    #[test]
+    fn test_declaration_errors() {
+        let col = |line| {
+            Param::parse(line, 1)
+                .expect("declaration")
+                .expect_err(line)
+                .span
+                .map(|s| s.col)
+        };
+        assert_eq!(col("param : int"), Some(7));
+        assert_eq!(col("param n: float"), Some(10));
+        assert_eq!(col("param n: int = many"), Some(16));
+        assert_eq!(col("param n: enum()"), Some(10));
+        assert_eq!(col("param 0: int"), Some(7));
+    }
+
+    #[test]
+    fn test_check() {
+        assert!(param("param n: int").check("-3").is_ok());
+        assert!(param("param b: bool").check("yes").is_err());
+        let err = param("param p: enum(a, b)").check("c").expect_err("c");
+        assert_eq!(err.to_string(), r#"-p: expected a|b, got "c""#);
+    }
+
+    #[test]
+    fn test_usage() {
+        let mut cc = param("param cc = gcc");
+        cc.description = Some("C compiler to use.".into());
+        let params = vec![
+            cc,
+            param("param profile: enum(debug, release)"),
+            param("param 1: path"),
+        ];
+        let want = "usage: run build.run [task] [-cc <string>] -profile <debug|release> <1>
+
+tasks: build, test
+
+parameters:
+  -cc <string>              C compiler to use.  (default: gcc)
+  -profile <debug|release>
+  <1> <path>";
+        assert_eq!(usage("build.run", &["build", "test"], &params), want);
+    }
+}
@@ -1,12 +1,23 @@
+use crate::ast::{self, Op};
+use crate::builtin;
 use crate::env::Environment;
-use crate::util::SplitWords;
+use crate::error::{Error, Span};
+use crate::lexer::{Fallback, Part, Word};
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::path::PathBuf;
+use std::time::Duration;
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Cmd {
     pub name: String, // Should this actually be a PathBuf?
     pub args: Vec<String>,
+    // Environment variables set for just this command.
+    pub env: Vec<(String, String)>,
+    // Arguments to expand as glob patterns when the command runs, as indices
+    // into args along with the pattern, in which quoted and substituted text
+    // is escaped.
+    pub globs: Vec<(usize, String)>,
 }
 
 #[derive(Debug, PartialEq)]
@@ -17,16 +28,191 @@ pub enum Item {
         // Terminus is the final destination for a pipeline.
         // Specifies to stream output into the file.
         terminus: Option<PathBuf>,
+        // Append to the terminus rather than truncating it.
+        append: bool,
+        // Where stderr goes, inherited if None.
+        stderr: Option<Stderr>,
+        // File streamed into stdin of the first command.
+        stdin: Option<PathBuf>,
         ignore_failure: bool,
+        // How long the pipeline may run for before it's killed, "timeout 30s".
+        timeout: Option<Duration>,
+        // How to rerun the pipeline if it fails, "retry 3".
+        retry: Option<Retry>,
         literal: String,
+        span: Span,
+    },
+    // Parallel items run concurrently with one another.
+    Parallel(Vec<Item>),
+    // Export sets environment variables for every later item, "export A=b".
+    Export(Vec<(String, String)>),
+    // Chdir changes the directory every later item runs in.
+    Chdir(Chdir, Span),
+    // Include runs another run file's items in place.
+    Include(Include),
+    // Let runs a pipeline and captures its output, trimmed, into a variable
+    // for later lines, "let rev = git rev-parse HEAD".
+    Let {
+        name: String,
+        pipeline: Box<Item>,
+    },
+    // Deferred is a line that uses variables captured by let, resolved once
+    // it's reached since their values aren't known before then.
+    Deferred(Deferred),
+    // Inputs declares the files, as glob patterns, that what follows reads.
+    Inputs(Vec<String>),
+    // Outputs declares the files that what follows writes.
+    Outputs(Vec<PathBuf>),
+    // Incremental items are skipped when their outputs are up to date with
+    // their inputs (see incremental.rs).
+    Incremental {
+        inputs: Vec<String>,
+        outputs: Vec<PathBuf>,
+        items: Vec<Item>,
     },
+    // Chain runs each item after the first depending on whether the one run
+    // before it succeeded, "&&", or failed, "||".
+    Chain {
+        first: Box<Item>,
+        rest: Vec<(Op, Item)>,
+    },
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Stderr {
+    // Stream into the file, "2> file".
+    File(PathBuf),
+    // Merge into stdout, wherever that goes, "2>&1".
+    Stdout,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Chdir {
+    // "cd dir"
+    Cd(PathBuf),
+    // "pushd dir", returned from by "popd".
+    Pushd(PathBuf),
+    // "popd"
+    Popd,
+}
+
+// Retry reruns a failing pipeline, "retry 3 1s", until it succeeds or has
+// run as many times as it may.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Retry {
+    // Times the pipeline may run, including the first.
+    pub attempts: u32,
+    // Wait before the second attempt, doubled before each one after that.
+    pub backoff: Duration,
+}
+
+// A line resolved once it's reached, see Item::Deferred.
+#[derive(Debug, PartialEq)]
+pub struct Deferred {
+    pub text: String,
+    // Number of the line in its run file, so that spans refer to it.
+    pub line: usize,
+    // Variables besides those captured by let, as the line was parsed with.
+    pub env: Environment,
+    pub ignore_failure: bool,
+}
+
+impl Deferred {
+    // Resolve the line into items, now that variables have been `captured`.
+    // A concurrent line was grouped with its neighbours when it was deferred,
+    // so it isn't grouped again.
+    pub fn resolve(&self, captured: &HashMap<String, String>) -> Result<Vec<Item>, Error> {
+        let mut env = self.env.clone();
+        env.named.extend(
+            captured
+                .iter()
+                .map(|(name, value)| (name.clone(), value.clone())),
+        );
+        let items = ItemParser { env: &env }.parse_at(&self.text, self.line)?;
+        Ok(items
+            .into_iter()
+            .flat_map(|item| match item {
+                Item::Parallel(items) => items,
+                item => vec![item],
+            })
+            .collect())
+    }
+}
+
+// Include runs the default task of another run file, "include common.run",
+// with the arguments given after it as though from the command line.
+#[derive(Debug, PartialEq)]
+pub struct Include {
+    // Path to the file, relative to the including file.
+    pub path: PathBuf,
+    pub args: Vec<String>,
+    pub span: Span,
+    // The file's items, filled in once it's loaded (see load.rs), along with
+    // its name and text for reporting errors against.
+    pub items: Vec<Item>,
+    pub file: String,
+    pub text: String,
+}
+
+impl Item {
+    // Name of the statement, for items that change the context later items
+    // run in rather than running anything themselves.
+    // Statements can't be chained or run concurrently, since the context is
+    // shared.
+    pub fn statement(&self) -> Option<&'static str> {
+        match self {
+            Item::Export(_) => Some("export"),
+            Item::Chdir(Chdir::Cd(_), _) => Some("cd"),
+            Item::Chdir(Chdir::Pushd(_), _) => Some("pushd"),
+            Item::Chdir(Chdir::Popd, _) => Some("popd"),
+            Item::Include(_) => Some("include"),
+            Item::Let { .. } => Some("let"),
+            Item::Inputs(_) => Some("inputs"),
+            Item::Outputs(_) => Some("outputs"),
+            _ => None,
+        }
+    }
+
+    pub fn ignore_failure(&self) -> bool {
+        match self {
+            Item::Pipeline { ignore_failure, .. } => *ignore_failure,
+            Item::Chain { first, .. } => first.ignore_failure(),
+            Item::Let { pipeline, .. } => pipeline.ignore_failure(),
+            Item::Deferred(deferred) => deferred.ignore_failure,
+            _ => false,
+        }
+    }
+
+    // Label identifies the item in output, eg when running concurrently.
+    pub fn label(&self) -> Option<String> {
+        match self {
+            Item::Pipeline { literal, .. } => Some(literal.trim_start_matches("- ").into()),
+            Item::Chain { first, rest } => {
+                let mut label = first.label()?;
+                for (op, item) in rest {
+                    label.push_str(&format!(" {} {}", op, item.label()?));
+                }
+                Some(label)
+            }
+            Item::Parallel(items) => {
+                let labels: Option<Vec<String>> = items.iter().map(Item::label).collect();
+                Some(labels?.join(" & "))
+            }
+            Item::Deferred(deferred) => {
+                let text = deferred.text.trim().trim_start_matches('&').trim_start();
+                Some(text.trim_start_matches("- ").into())
+            }
+            _ => None,
+        }
+    }
 }
 
 pub struct ItemParser<'a> {
     pub env: &'a Environment,
 }
 
-// Parsing is done very simple, line-wise, semicolon-wise, then pipe-wise.
+// Parsing is done line-wise: each line is lexed and parsed into a syntax tree
+// (see ast.rs), which is then resolved against the environment into items.
 //
 // Single command on a line:
 //  command arg\n
@@ -37,134 +223,601 @@ pub struct ItemParser<'a> {
 //  command arg | command arg | command arg ; final_command\n
 //  ^---------^   ^---------^   ^---------^   ^-----------^
 //
-// Only the actual command parsing requires the environment.
+// Pipelines joined by "&&" or "||" form a chain, which runs like it would in
+// a shell:
+//
+//  cargo build && cargo test || echo failed\n
+//
+// Consecutive lines prefixed with "& " form a group that runs concurrently:
+//
+//  & cargo build
+//  & cargo test
+//
+// Declaring inputs and outputs makes what follows incremental. Declarations
+// before anything else cover everything after them, otherwise they cover the
+// next command, or group of concurrent commands:
+//
+//  inputs src/*.c include/*.h
+//  outputs app.exe
+//  gcc src/*.c -o app.exe
+//
+// "let" captures a command's output into a variable for later lines:
+//
+//  let rev = git rev-parse --short HEAD
+//  docker build -t app:$(rev) .
+//
+// Only resolving variables requires the environment. Lines using variables
+// captured by let are resolved once they're reached, and the rest up front.
 impl<'a> ItemParser<'a> {
     // Parse a string buffer into a list of command items.
-    // Note: Reports the first error encountered and discards the rest.
-    pub fn parse(&self, s: &str) -> Result<Vec<Item>, String> {
-        Ok(s.lines()
-            .map(|s| s.trim())
-            .filter(|s| !s.is_empty())
-            .map(|s| {
-                if s.starts_with("//") {
-                    Ok(vec![Item::Comment(s.into())])
-                } else {
-                    s.split(";").map(|s| self.parse_pipeline(s)).collect()
+    pub fn parse(&self, s: &str) -> Result<Vec<Item>, Error> {
+        self.parse_at(s, 1)
+    }
+
+    // Parse a string buffer that starts on the given line of a run file, so
+    // that spans refer to the run file rather than to the buffer.
+    // Every line is checked before any is resolved, so that all syntax errors
+    // and missing variables are reported at once.
+    pub fn parse_at(&self, s: &str, first_line: usize) -> Result<Vec<Item>, Error> {
+        let mut errors = vec![];
+        let mut parsed = vec![];
+        // Variables captured by let lines so far.
+        let mut bound = HashSet::new();
+        for (number, raw) in s
+            .lines()
+            .enumerate()
+            .map(|(index, raw)| (first_line + index, raw))
+            .filter(|(_, raw)| !raw.trim().is_empty())
+        {
+            if raw.trim().starts_with("//") {
+                parsed.push((number, raw, None));
+                continue;
+            }
+            match ast::parse_line(raw, number) {
+                Ok(line) => {
+                    errors.extend(self.check(&line, &bound));
+                    bound.extend(binds(&line));
+                    parsed.push((number, raw, Some(line)));
                 }
+                Err(err) => errors.push(err),
+            }
+        }
+        Error::all(errors)?;
+
+        let lines = parsed
+            .into_iter()
+            .map(|(number, raw, line)| {
+                let span = Span::of(number, raw, raw.trim());
+                let (parallel, items) = match line {
+                    Some(line) if uses(&line, &bound) => self.defer(number, raw, line)?,
+                    Some(line) => {
+                        let items = line
+                            .chains
+                            .into_iter()
+                            .map(|chain| self.chain(raw, chain))
+                            .collect::<Result<Vec<_>, _>>()?;
+                        (line.parallel, items)
+                    }
+                    None => (false, vec![Item::Comment(raw.trim().into())]),
+                };
+                Ok((span, parallel, items))
             })
-            .collect::<Result<Vec<Vec<Item>>, String>>()?
+            .collect::<Result<Vec<(Span, bool, Vec<Item>)>, Error>>()?;
+
+        // Gather consecutive concurrent lines into a single parallel group.
+        let mut items = vec![];
+        let mut concurrent = false;
+        // The first input or output declaration not yet followed by a command.
+        let mut dangling = None;
+        for (span, parallel, line) in lines {
+            if let Some(name) = line.iter().filter(|_| parallel).find_map(Item::statement) {
+                return Err(Error::parse(format!("{} can't run concurrently", name)).at(span));
+            }
+            match line.first() {
+                Some(Item::Inputs(_) | Item::Outputs(_)) => {
+                    dangling.get_or_insert(span);
+                }
+                Some(item) if item.statement().is_none() && !matches!(item, Item::Comment(_)) => {
+                    dangling = None;
+                }
+                _ => {}
+            }
+            if !parallel {
+                items.extend(line);
+            } else if let (true, Some(Item::Parallel(group))) = (concurrent, items.last_mut()) {
+                group.extend(line);
+            } else {
+                items.push(Item::Parallel(line));
+            }
+            concurrent = parallel;
+        }
+        incremental(items, true).map_err(|err| match dangling {
+            Some(span) => err.at(span),
+            None => err,
+        })
+    }
+
+    // Resolve a parsed chain from the line `raw`.
+    // A chain of one is just its pipeline.
+    fn chain(&self, raw: &str, chain: ast::Chain) -> Result<Item, Error> {
+        let first = self.pipeline(raw, chain.first)?;
+        if chain.rest.is_empty() {
+            return Ok(first);
+        }
+        let rest = chain
+            .rest
             .into_iter()
-            .flatten()
-            .collect())
+            .map(|(op, pipeline)| Ok((op, self.pipeline(raw, pipeline)?)))
+            .collect::<Result<Vec<_>, Error>>()?;
+        if let Some(name) = std::iter::once(&first)
+            .chain(rest.iter().map(|(_, item)| item))
+            .find_map(Item::statement)
+        {
+            return Err(Error::parse(format!("{} can't be chained", name)));
+        }
+        Ok(Item::Chain {
+            first: Box::new(first),
+            rest,
+        })
     }
 
-    // Parse a pipeline of commands into a pipeline structure.
-    // "cat src/main.rs | rg match | head > output.txt"
-    fn parse_pipeline(&self, s: &str) -> Result<Item, String> {
-        let literal = s;
+    // Resolve a statement, such as "export" or "cd", into its item.
+    fn statement(&self, name: &str, pipeline: &ast::Pipeline) -> Result<Item, Error> {
+        let redirected =
+            pipeline.terminus.is_some() || pipeline.stderr.is_some() || pipeline.stdin.is_some();
+        let cmd = &pipeline.cmds[0];
+        if pipeline.cmds.len() > 1 || redirected || !cmd.assignments.is_empty() {
+            return Err(Error::parse(format!(
+                "{} can't be piped, redirected or given variables",
+                name
+            ))
+            .at(pipeline.span));
+        }
+        let args = &cmd.words[1..];
+        match name {
+            "export" => self.export(args),
+            "inputs" | "outputs" => {
+                if args.is_empty() {
+                    return Err(Error::parse(format!("{} expects files", name)).at(cmd.span));
+                }
+                let paths = args
+                    .iter()
+                    .map(|arg| self.resolve(arg))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(match name {
+                    "inputs" => Item::Inputs(paths),
+                    _ => Item::Outputs(paths.into_iter().map(PathBuf::from).collect()),
+                })
+            }
+            "include" => match args {
+                [path, args @ ..] => Ok(Item::Include(Include {
+                    path: self.resolve(path)?.into(),
+                    args: args
+                        .iter()
+                        .map(|arg| self.resolve(arg))
+                        .collect::<Result<_, _>>()?,
+                    span: pipeline.span,
+                    items: vec![],
+                    file: String::new(),
+                    text: String::new(),
+                })),
+                [] => Err(Error::parse("include expects a file").at(cmd.span)),
+            },
+            "popd" => match args {
+                [] => Ok(Item::Chdir(Chdir::Popd, pipeline.span)),
+                [extra, ..] => Err(Error::parse("popd takes no arguments").at(extra.span)),
+            },
+            _ => match args {
+                [dir] => {
+                    let dir = self.resolve(dir)?.into();
+                    let change = match name {
+                        "cd" => Chdir::Cd(dir),
+                        _ => Chdir::Pushd(dir),
+                    };
+                    Ok(Item::Chdir(change, pipeline.span))
+                }
+                _ => Err(Error::parse(format!("{} expects a directory", name)).at(cmd.span)),
+            },
+        }
+    }
 
-        let (s, ignore_failure) = if s.starts_with("- ") {
-            (s.trim_start_matches("- "), true)
-        } else {
-            (s, false)
+    // Resolve "let NAME = pipeline" into an item capturing what the pipeline
+    // writes to stdout.
+    fn bind(&self, raw: &str, mut pipeline: ast::Pipeline) -> Result<Item, Error> {
+        let cmd = &mut pipeline.cmds[0];
+        let name = match cmd.words.as_slice() {
+            [_, name, equals, _, ..] if equals.bare() == Some("=") => name,
+            _ => return Err(Error::parse("expected let NAME = command").at(cmd.span)),
         };
-
-        let mut terminus = None;
-        let mut cmds = s.split(" | ").collect::<Vec<_>>();
-
-        let last = match cmds.last() {
-            Some(last) => last,
-            None => return Err("no commands to parse".into()),
+        let name = match name.bare() {
+            Some(text) if is_name(text) => text.to_owned(),
+            _ => return Err(Error::parse("let expects a variable name").at(name.span)),
         };
+        if pipeline.terminus.is_some() {
+            return Err(
+                Error::parse("let captures output, so it can't be redirected").at(pipeline.span),
+            );
+        }
+        cmd.words.drain(..3);
+        let span = cmd.span;
+        let pipeline = self.pipeline(raw, pipeline)?;
+        if let Some(statement) = pipeline.statement() {
+            return Err(Error::parse(format!("let can't capture {}", statement)).at(span));
+        }
+        Ok(Item::Let {
+            name,
+            pipeline: Box::new(pipeline),
+        })
+    }
 
-        // If the final command contains a " > ", break it off and use it as the
-        // terminus redirection.
-        // Note: Doesn't consider bad input like " > > ".
-        if let Some(index) = last.find(" > ") {
-            let (last, t) = last.split_at(index);
-            cmds.remove(cmds.len() - 1);
-            cmds.push(last);
-            terminus = Some(t.trim_start_matches(" > ").into());
+    // Leave a line using variables captured by let to be resolved once it's
+    // reached. Declarations and includes are needed before anything runs, so
+    // they can't use them.
+    fn defer(&self, number: usize, raw: &str, line: ast::Line) -> Result<(bool, Vec<Item>), Error> {
+        let first = &line.chains[0].first;
+        if line.parallel && binds(&line).is_some() {
+            return Err(Error::parse("let can't run concurrently").at(first.span));
+        }
+        if let Some(name @ ("include" | "inputs" | "outputs")) = first.cmds[0].words[0].bare() {
+            return Err(
+                Error::parse(format!("{} can't use variables captured by let", name))
+                    .at(first.span),
+            );
         }
+        let deferred = Deferred {
+            text: raw.into(),
+            line: number,
+            env: self.env.clone(),
+            ignore_failure: first.ignore_failure,
+        };
+        Ok((line.parallel, vec![Item::Deferred(deferred)]))
+    }
 
-        let cmds = cmds
-            .into_iter()
-            .map(|s| SplitWords {
-                src: s.chars().peekable(),
+    // Resolve the arguments of "export NAME=value..." into an export item.
+    fn export(&self, args: &[Word]) -> Result<Item, Error> {
+        let vars = args
+            .iter()
+            .map(|word| match ast::assignment(word) {
+                Some((name, value)) => Ok((name, self.resolve(&value)?)),
+                None => Err(Error::parse("expected NAME=value").at(word.span)),
             })
-            .map(|mut words| -> Result<Cmd, String> {
-                match words.next() {
-                    Some(name) => Ok(Cmd {
-                        name: name.to_owned(),
-                        args: words
-                            .map(String::from)
-                            .map(|arg| self.parse_argument(arg))
-                            .collect::<Result<Vec<_>, _>>()?,
-                    }),
-                    None => Err("empty command".into()),
-                }
+            .collect::<Result<Vec<_>, Error>>()?;
+        Ok(Item::Export(vars))
+    }
+
+    // Resolve a parsed pipeline from the line `raw` into a pipeline item.
+    fn pipeline(&self, raw: &str, mut pipeline: ast::Pipeline) -> Result<Item, Error> {
+        // Prefixes for how the pipeline runs, in either order.
+        let (mut timeout, mut retry) = (None, None);
+        loop {
+            let cmd = &mut pipeline.cmds[0];
+            match cmd.words[0].bare() {
+                Some("timeout") if timeout.is_none() => timeout = Some(self.timeout(cmd)?),
+                Some("retry") if retry.is_none() => retry = Some(self.retry(cmd)?),
+                _ => break,
+            }
+        }
+        if let Some(
+            name @ ("export" | "cd" | "pushd" | "popd" | "include" | "inputs" | "outputs" | "let"),
+        ) = pipeline.cmds[0].words[0].bare()
+        {
+            // A let that fails captures nothing, but other statements have
+            // nothing sensible to fall back to.
+            let prefixed = [
+                (timeout.is_some(), "given a timeout"),
+                (retry.is_some(), "retried"),
+                (
+                    pipeline.ignore_failure && name != "let",
+                    "ignored if it fails",
+                ),
+            ];
+            if let Some((_, what)) = prefixed.iter().find(|(prefixed, _)| *prefixed) {
+                return Err(
+                    Error::parse(format!("{} can't be {}", name, what)).at(pipeline.cmds[0].span)
+                );
+            }
+            if name == "let" {
+                return self.bind(raw, pipeline);
+            }
+            return self.statement(name, &pipeline);
+        }
+
+        let cmds = pipeline
+            .cmds
+            .iter()
+            .map(|cmd| {
+                let (vars, words) = unwrap_env(&cmd.words);
+                let mut resolved = words.iter().map(|word| self.resolve(word));
+                Ok(Cmd {
+                    name: resolved.next().expect("commands are never empty")?,
+                    args: resolved.collect::<Result<Vec<_>, _>>()?,
+                    env: cmd
+                        .assignments
+                        .iter()
+                        .chain(&vars)
+                        .map(|(name, value)| Ok((name.clone(), self.resolve(value)?)))
+                        .collect::<Result<Vec<_>, Error>>()?,
+                    globs: words[1..]
+                        .iter()
+                        .enumerate()
+                        .map(|(index, word)| Ok(self.pattern(word)?.map(|p| (index, p))))
+                        .filter_map(Result::transpose)
+                        .collect::<Result<Vec<_>, Error>>()?,
+                })
             })
-            .collect::<Result<Vec<_>, _>>()?;
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        let terminus = match &pipeline.terminus {
+            Some(word) => Some(self.resolve(word)?.into()),
+            None => None,
+        };
 
+        let stderr = match &pipeline.stderr {
+            Some(ast::Stderr::File(word)) => Some(Stderr::File(self.resolve(word)?.into())),
+            Some(ast::Stderr::Stdout) => Some(Stderr::Stdout),
+            None => None,
+        };
+
+        let stdin = match &pipeline.stdin {
+            Some(word) => Some(self.resolve(word)?.into()),
+            None => None,
+        };
+
+        // The literal leaves out prefixes, so that it's the command as echoed
+        // and labelled.
+        let span = pipeline.span;
+        let start = match (timeout, retry) {
+            (None, None) => span.col,
+            _ => pipeline.cmds[0].words[0].span.col,
+        };
+        let mut literal: String = raw
+            .chars()
+            .skip(start - 1)
+            .take(span.col + span.len - start)
+            .collect();
+        if start != span.col && pipeline.ignore_failure {
+            literal.insert_str(0, "- ");
+        }
         Ok(Item::Pipeline {
             cmds,
             terminus,
-            ignore_failure,
-            literal: literal.into(),
+            append: pipeline.append,
+            stderr,
+            stdin,
+            ignore_failure: pipeline.ignore_failure,
+            timeout,
+            retry,
+            literal,
+            span,
         })
     }
 
-    fn parse_argument(&self, arg: String) -> Result<String, String> {
-        // Basically, if arg is "$(<numeric>)" we parse
-        // the number and lookup the corresponding positional argument.
-        // If arg is "$(<identifier>)" we lookup the named argument.
-        // If either one doesn't exist we throw up an error.
-        if arg.contains('$') {
-            let mut ident = String::new();
-            let mut prefix = String::new();
-            let mut suffix = String::new();
-            let mut stream = arg.chars().peekable();
-
-            while let Some(c) = stream.next() {
-                if c == '$' {
-                    if let Some(p) = stream.peek() {
-                        if *p == '(' {
-                            stream.next();
-                            while let Some(c) = stream.next() {
-                                if c == ')' {
-                                    break;
-                                }
-                                ident.push(c);
-                            }
-                            while let Some(c) = stream.next() {
-                                suffix.push(c);
-                            }
-                        }
-                    } else {
-                        prefix.push(c);
-                    }
-                } else {
-                    prefix.push(c);
-                }
+    // Take the "timeout DURATION" prefix off a command, returning the duration.
+    fn timeout(&self, cmd: &mut ast::Command) -> Result<Duration, Error> {
+        let word = match cmd.words.get(1) {
+            Some(word) if cmd.words.len() > 2 => word,
+            _ => return Err(Error::parse("timeout expects a duration and a command").at(cmd.span)),
+        };
+        let text = self.resolve(word)?;
+        let timeout = builtin::duration(&text)
+            .ok_or_else(|| Error::parse(format!("invalid duration: {}", text)).at(word.span))?;
+        cmd.words.drain(..2);
+        Ok(timeout)
+    }
+
+    // Take the "retry ATTEMPTS [BACKOFF]" prefix off a command.
+    // The backoff is told apart from the command by its unit, eg "2s".
+    fn retry(&self, cmd: &mut ast::Command) -> Result<Retry, Error> {
+        let word = match cmd.words.get(1) {
+            Some(word) if cmd.words.len() > 2 => word,
+            _ => {
+                return Err(
+                    Error::parse("retry expects a number of attempts and a command").at(cmd.span),
+                )
+            }
+        };
+        let text = self.resolve(word)?;
+        let attempts = text
+            .parse::<u32>()
+            .ok()
+            .filter(|attempts| *attempts > 0)
+            .ok_or_else(|| {
+                Error::parse(format!("invalid number of attempts: {}", text)).at(word.span)
+            })?;
+        let backoff = match self.resolve(&cmd.words[2])? {
+            text if text.parse::<f64>().is_err() => builtin::duration(&text),
+            _ => None,
+        };
+        let prefix = if backoff.is_some() { 3 } else { 2 };
+        if cmd.words.len() <= prefix {
+            return Err(Error::parse("retry expects a command").at(cmd.span));
+        }
+        cmd.words.drain(..prefix);
+        Ok(Retry {
+            attempts,
+            backoff: backoff.unwrap_or_default(),
+        })
+    }
+
+    // Check that every variable in the line has a value to resolve to, or
+    // will have once the let lines capturing them have run.
+    fn check(&self, line: &ast::Line, bound: &HashSet<String>) -> Vec<Error> {
+        line.words()
+            .into_iter()
+            .flat_map(|word| &word.parts)
+            .filter_map(|part| match part {
+                Part::Var { name, .. } if bound.contains(name) => None,
+                Part::Var {
+                    name,
+                    fallback,
+                    span,
+                } => self.var(name, fallback, *span).err(),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // Resolve a word into a single argument by substituting its variables.
+    fn resolve(&self, word: &Word) -> Result<String, Error> {
+        let mut arg = String::new();
+        for part in &word.parts {
+            match part {
+                Part::Lit(text) | Part::Quoted(text) => arg.push_str(text),
+                Part::Var {
+                    name,
+                    fallback,
+                    span,
+                } => arg.push_str(&self.var(name, fallback, *span)?),
+            }
+        }
+        Ok(arg)
+    }
+
+    // Resolve a word into a glob pattern if it has an unquoted wildcard,
+    // escaping everything else so that only the wildcards as written match.
+    // Invalid patterns, eg "[", are left as they are.
+    fn pattern(&self, word: &Word) -> Result<Option<String>, Error> {
+        let wild = |text: &str| text.contains(['*', '?', '[']);
+        if !word
+            .parts
+            .iter()
+            .any(|p| matches!(p, Part::Lit(text) if wild(text)))
+        {
+            return Ok(None);
+        }
+        let mut pattern = String::new();
+        for part in &word.parts {
+            match part {
+                Part::Lit(text) => pattern.push_str(text),
+                Part::Quoted(text) => pattern.push_str(&glob::Pattern::escape(text)),
+                Part::Var {
+                    name,
+                    fallback,
+                    span,
+                } => pattern.push_str(&glob::Pattern::escape(&self.var(name, fallback, *span)?)),
+            }
+        }
+        Ok(glob::Pattern::new(&pattern).ok().map(|_| pattern))
+    }
+
+    // Value of a variable, falling back as the run file asks if it has none.
+    fn var(&self, name: &str, fallback: &Option<Fallback>, span: Span) -> Result<String, Error> {
+        match (self.lookup(name), fallback) {
+            (Some(value), _) => Ok(value),
+            (None, Some(Fallback::Default(value))) => Ok(value.clone()),
+            (None, Some(Fallback::Error(msg))) if !msg.is_empty() => {
+                Err(Error::variable(name, Some(msg.clone())).at(span))
             }
+            (None, _) => Err(Error::variable(name, None).at(span)),
+        }
+    }
+
+    // Basically, if the name is "<numeric>" we parse the number and lookup the
+    // corresponding positional argument.
+    // If the name is "env:<identifier>" we lookup the process environment.
+    // If the name is "<identifier>" we lookup the named argument.
+    fn lookup(&self, name: &str) -> Option<String> {
+        if let Some(name) = name.strip_prefix("env:") {
+            return std::env::var(name).ok();
+        }
+        match name.parse::<usize>() {
+            Ok(index) => index
+                .checked_sub(1)
+                .and_then(|index| self.env.positional.get(index))
+                .cloned(),
+            Err(_) => self.env.named.get(name).cloned(),
+        }
+    }
+}
+
+// Split "env NAME=value... cmd" into the variables and the command, which runs
+// with them like "NAME=value cmd" would, so that it works without an env
+// program. Anything else, like env's own flags, is left to env.
+fn unwrap_env(words: &[Word]) -> (Vec<(String, Word)>, &[Word]) {
+    if words[0].bare() == Some("env") {
+        let vars: Vec<(String, Word)> = words[1..].iter().map_while(ast::assignment).collect();
+        let rest = &words[1 + vars.len()..];
+        if rest
+            .first()
+            .is_some_and(|word| !word.bare().is_some_and(|text| text.starts_with('-')))
+        {
+            return (vars, rest);
+        }
+    }
+    (vec![], words)
+}
 
-            let value = match ident.parse::<usize>() {
-                Ok(index) => self.env.positional.get(index - 1),
-                Err(_) => self.env.named.get(&ident),
-            };
+// Name of the variable the line captures into, if it's a let.
+fn binds(line: &ast::Line) -> Option<String> {
+    let cmd = line.chains.first()?.first.cmds.first()?;
+    match cmd.words.as_slice() {
+        [first, name, ..] if first.bare() == Some("let") => name.bare().map(String::from),
+        _ => None,
+    }
+}
 
-            match value {
-                Some(value) => Ok(format!("{}{}{}", prefix, value, suffix)),
-                None => Err(format!("no value specified for argument: {}", ident,)),
+// Whether the line uses any of the `bound` variables.
+fn uses(line: &ast::Line, bound: &HashSet<String>) -> bool {
+    line.words()
+        .into_iter()
+        .flat_map(|word| &word.parts)
+        .any(|part| matches!(part, Part::Var { name, .. } if bound.contains(name)))
+}
+
+// Whether let can capture into `name`: not a number, which would be
+// positional, nor anything with characters that mean something else in a
+// variable reference.
+fn is_name(name: &str) -> bool {
+    let valid = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-';
+    !name.is_empty() && !name.starts_with(|c: char| c.is_ascii_digit()) && name.chars().all(valid)
+}
+
+// Wrap whatever input and output declarations cover into incremental items.
+// Declarations `leading` every command cover all of them, later ones cover the
+// next. Comments and statements are never covered.
+fn incremental(items: Vec<Item>, mut leading: bool) -> Result<Vec<Item>, Error> {
+    let mut grouped = vec![];
+    let (mut inputs, mut outputs) = (vec![], vec![]);
+    let mut items = items.into_iter();
+    while let Some(item) = items.next() {
+        match item {
+            Item::Inputs(paths) => inputs.extend(paths),
+            Item::Outputs(paths) => outputs.extend(paths),
+            // Let always runs, since later lines need what it captures.
+            Item::Comment(_) | Item::Export(_) | Item::Chdir(..) | Item::Let { .. } => {
+                grouped.push(item)
+            }
+            item if inputs.is_empty() && outputs.is_empty() => {
+                leading = false;
+                grouped.push(item);
+            }
+            item => {
+                let mut covered = vec![item];
+                if leading {
+                    covered.extend(incremental(items.by_ref().collect(), false)?);
+                }
+                leading = false;
+                grouped.push(Item::Incremental {
+                    inputs: std::mem::take(&mut inputs),
+                    outputs: std::mem::take(&mut outputs),
+                    items: covered,
+                });
             }
-        } else {
-            Ok(arg)
         }
     }
+    if !inputs.is_empty() || !outputs.is_empty() {
+        return Err(Error::parse(
+            "inputs and outputs must be followed by a command",
+        ));
+    }
+    Ok(grouped)
 }
 
 impl fmt::Display for Cmd {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (name, value) in &self.env {
+            write!(f, "{}={} ", name, value)?;
+        }
         write!(f, "{} {}", self.name, self.args.join(" "))?;
         Ok(())
     }
@@ -173,6 +826,7 @@ impl fmt::Display for Cmd {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::error::ErrorKind;
     use pretty_assertions::assert_eq;
     use std::collections::HashMap;
 
@@ -193,11 +847,23 @@ mod tests {
         let input = r#"ident v$(Version) $(Bin).exe"#;
         let want = vec![Item::Pipeline {
             ignore_failure: false,
+            timeout: None,
+            retry: None,
             terminus: None,
+            append: false,
+            stderr: None,
+            stdin: None,
             literal: input.into(),
+            span: Span {
+                line: 1,
+                col: 1,
+                len: input.len(),
+            },
             cmds: vec![Cmd {
                 name: "ident".into(),
                 args: vec!["v0.3.0".into(), "binary.exe".into()],
+                env: vec![],
+                globs: vec![],
             }],
         }];
         let got = ItemParser {
@@ -206,7 +872,7 @@ mod tests {
                 positional: vec![],
             },
         }
-        .parse(&input)
+        .parse(input)
         .unwrap();
         assert_eq!(got, want);
     }
@@ -216,11 +882,23 @@ mod tests {
         let input = r#"ident v$(1) $(2).exe"#;
         let want = vec![Item::Pipeline {
             ignore_failure: false,
+            timeout: None,
+            retry: None,
             terminus: None,
+            append: false,
+            stderr: None,
+            stdin: None,
             literal: input.into(),
+            span: Span {
+                line: 1,
+                col: 1,
+                len: input.len(),
+            },
             cmds: vec![Cmd {
                 name: "ident".into(),
                 args: vec!["v0.3.0".into(), "binary.exe".into()],
+                env: vec![],
+                globs: vec![],
             }],
         }];
         let got = ItemParser {
@@ -229,11 +907,82 @@ mod tests {
                 positional: vec!["0.3.0".into(), "binary".into()],
             },
         }
-        .parse(&input)
+        .parse(input)
         .unwrap();
         assert_eq!(got, want);
     }
 
+    #[test]
+    fn test_missing_variable_span() {
+        let input = "// Build.\n  echo ok ; cc -o $(Out).exe main.c";
+        let err = ItemParser {
+            env: &Environment::default(),
+        }
+        .parse_at(input, 4)
+        .expect_err("missing variable");
+        assert!(matches!(&err.kind, ErrorKind::Variable { name, .. } if name == "Out"));
+        assert_eq!(
+            err.span,
+            Some(Span {
+                line: 5,
+                col: 19,
+                len: 6,
+            })
+        );
+    }
+
+    #[test]
+    fn test_variable_fallbacks() {
+        let parser = ItemParser {
+            env: &Environment {
+                named: map! {"cc" => "clang"},
+                positional: vec![],
+            },
+        };
+        let got = parser
+            .parse("$(cc:-gcc) $(lib:-libc.a) $(1:-first)")
+            .expect("parsing");
+        match got.as_slice() {
+            [Item::Pipeline { cmds, .. }] => {
+                assert_eq!(cmds[0].name, "clang");
+                assert_eq!(cmds[0].args, vec!["libc.a", "first"]);
+            }
+            items => panic!("expected a single pipeline, got {:?}", items),
+        }
+
+        let err = parser
+            .parse("echo $(target:?must pass -target)")
+            .expect_err("missing target");
+        assert_eq!(err.to_string(), "target: must pass -target");
+    }
+
+    #[test]
+    fn test_all_missing_variables() {
+        let input = "echo $(a)\n// $(not_a_variable)\necho $(b) > $(c:?out file)\necho |";
+        let err = ItemParser {
+            env: &Environment::default(),
+        }
+        .parse(input)
+        .expect_err("missing variables");
+        let errors = match err.kind {
+            ErrorKind::Many(errors) => errors,
+            kind => panic!("expected many errors, got {:?}", kind),
+        };
+        let got: Vec<_> = errors
+            .iter()
+            .map(|err| (err.to_string(), err.span.map(|s| (s.line, s.col))))
+            .collect();
+        assert_eq!(
+            got,
+            vec![
+                ("no value specified for argument: a".into(), Some((1, 6))),
+                ("no value specified for argument: b".into(), Some((3, 6))),
+                ("c: out file".into(), Some((3, 13))),
+                ("empty command".into(), Some((4, 7))),
+            ]
+        );
+    }
+
     #[test]
     fn test_pipeline_parsing() {
         let input = r#"cat src/main.rs | rg "|" | head 5"#;
@@ -241,32 +990,329 @@ mod tests {
             Cmd {
                 name: "cat".into(),
                 args: vec!["src/main.rs".into()],
+                env: vec![],
+                globs: vec![],
             },
             Cmd {
                 name: "rg".into(),
                 args: vec!["|".into()],
+                env: vec![],
+                globs: vec![],
             },
             Cmd {
                 name: "head".into(),
                 args: vec!["5".into()],
+                env: vec![],
+                globs: vec![],
             },
         ];
         let got = ItemParser {
             env: &Environment::default(),
         }
-        .parse(&input)
+        .parse(input)
         .expect("parsing");
         assert_eq!(
             got,
             vec![Item::Pipeline {
                 ignore_failure: false,
+                timeout: None,
+                retry: None,
                 terminus: None,
+                append: false,
+                stderr: None,
+                stdin: None,
                 cmds: want,
-                literal: input.into()
+                literal: input.into(),
+                span: Span {
+                    line: 1,
+                    col: 1,
+                    len: input.len(),
+                },
             }]
         );
     }
 
+    // Input, commands (name then args) and terminus.
+    type Case<'a> = (&'a str, Vec<Vec<&'a str>>, Option<&'a str>);
+
+    // Conformance cases for the grammar: each input is parsed into a single
+    // pipeline.
+    #[test]
+    fn test_conformance() {
+        let cases: Vec<Case> = vec![
+            ("a|b", vec![vec!["a"], vec!["b"]], None),
+            ("a | b", vec![vec!["a"], vec!["b"]], None),
+            (r#"echo "a;b""#, vec![vec!["echo", "a;b"]], None),
+            (r#"rg " | " src"#, vec![vec!["rg", " | ", "src"]], None),
+            (r#"rg '>' src"#, vec![vec!["rg", ">", "src"]], None),
+            ("cat f>out.txt", vec![vec!["cat", "f"]], Some("out.txt")),
+            (
+                r#"cat f > "my file.txt""#,
+                vec![vec!["cat", "f"]],
+                Some("my file.txt"),
+            ),
+            (r"echo a\|b a\;b", vec![vec!["echo", "a|b", "a;b"]], None),
+            (
+                r#"echo "say \"hi\"""#,
+                vec![vec!["echo", r#"say "hi""#]],
+                None,
+            ),
+            (
+                r#"echo 'single "quoted"'"#,
+                vec![vec!["echo", r#"single "quoted""#]],
+                None,
+            ),
+            (r#"echo "" ''"#, vec![vec!["echo", "", ""]], None),
+            (
+                r"C:\tools\cc.exe -o C:\out",
+                vec![vec![r"C:\tools\cc.exe", "-o", r"C:\out"]],
+                None,
+            ),
+            ("echo $HOME $", vec![vec!["echo", "$HOME", "$"]], None),
+            (
+                r#"echo v$(Version)"-"$(1)"#,
+                vec![vec!["echo", "v0.3.0-first"]],
+                None,
+            ),
+            (
+                r#"echo "$(Version) \$(Version)""#,
+                vec![vec!["echo", "0.3.0 $(Version)"]],
+                None,
+            ),
+            ("$(Bin) --flag", vec![vec!["binary", "--flag"]], None),
+        ];
+        let parser = ItemParser {
+            env: &Environment {
+                named: map! {"Version" => "0.3.0", "Bin" => "binary"},
+                positional: vec!["first".into()],
+            },
+        };
+        for (input, want_cmds, want_terminus) in cases {
+            let items = parser.parse(input).expect(input);
+            match items.as_slice() {
+                [Item::Pipeline { cmds, terminus, .. }] => {
+                    let got: Vec<Vec<&str>> = cmds
+                        .iter()
+                        .map(|cmd| {
+                            std::iter::once(cmd.name.as_str())
+                                .chain(cmd.args.iter().map(String::as_str))
+                                .collect()
+                        })
+                        .collect();
+                    assert_eq!(got, want_cmds, "{}", input);
+                    assert_eq!(
+                        terminus.as_ref().and_then(|t| t.to_str()),
+                        want_terminus,
+                        "{}",
+                        input
+                    );
+                }
+                items => panic!("{}: expected a single pipeline, got {:?}", input, items),
+            }
+        }
+    }
+
+    #[test]
+    fn test_multiple_pipelines() {
+        let input = r#"echo "a;b" ; - echo c;echo d"#;
+        let got = ItemParser {
+            env: &Environment::default(),
+        }
+        .parse(input)
+        .expect("parsing");
+        let literals: Vec<_> = got
+            .iter()
+            .map(|item| match item {
+                Item::Pipeline {
+                    literal,
+                    ignore_failure,
+                    ..
+                } => (literal.as_str(), *ignore_failure),
+                item => panic!("unexpected item: {:?}", item),
+            })
+            .collect();
+        assert_eq!(
+            literals,
+            vec![
+                (r#"echo "a;b""#, false),
+                ("- echo c", true),
+                ("echo d", false)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_chain() {
+        let input = "- cargo build && cargo test || echo $(msg); echo done";
+        let got = ItemParser {
+            env: &Environment {
+                named: map! {"msg" => "failed"},
+                positional: vec![],
+            },
+        }
+        .parse(input)
+        .expect("parsing");
+        assert_eq!(got.len(), 2);
+        match &got[0] {
+            Item::Chain { first, rest } => {
+                assert_eq!(first.label(), Some("cargo build".into()));
+                let ops: Vec<_> = rest.iter().map(|(op, _)| *op).collect();
+                assert_eq!(ops, vec![Op::And, Op::Or]);
+                assert_eq!(
+                    rest[1].1.label(),
+                    Some("echo $(msg)".into()),
+                    "literal is unresolved"
+                );
+            }
+            item => panic!("unexpected item: {:?}", item),
+        }
+        assert!(got[0].ignore_failure());
+        assert_eq!(
+            got[0].label(),
+            Some("cargo build && cargo test || echo $(msg)".into())
+        );
+        assert!(matches!(got[1], Item::Pipeline { .. }));
+    }
+
+    #[test]
+    fn test_incremental() {
+        let parser = ItemParser {
+            env: &Environment::default(),
+        };
+        let shape = |items: &[Item]| -> Vec<String> {
+            items
+                .iter()
+                .map(|item| match item {
+                    Item::Incremental {
+                        inputs,
+                        outputs,
+                        items,
+                    } => format!("{:?} -> {:?}: {}", inputs, outputs, items.len()),
+                    item => item.label().unwrap_or_else(|| "-".into()),
+                })
+                .collect()
+        };
+
+        let got = parser
+            .parse("inputs src/*.c\n// Build.\noutputs app\ncc src/*.c\ncc test.c")
+            .expect("parsing");
+        // Comments pass through rather than being covered.
+        assert_eq!(shape(&got), vec!["-", r#"["src/*.c"] -> ["app"]: 2"#]);
+
+        let got = parser
+            .parse("cc a.c\ninputs b.c\noutputs b.o\n& cc b.c\n& cc c.c\ncc d.c")
+            .expect("parsing");
+        assert_eq!(
+            shape(&got),
+            vec!["cc a.c", r#"["b.c"] -> ["b.o"]: 1"#, "cc d.c"]
+        );
+
+        assert!(parser.parse("inputs a.c && cc a.c").is_err());
+        // Reported at the declaration that isn't followed by a command, or
+        // the line running one concurrently.
+        for (input, line) in [
+            ("cc a.c\noutputs a.o\n// Done.", 2),
+            ("& cc a.c\n& inputs a.c", 2),
+            ("cc a.c\n& export A=1", 2),
+        ] {
+            let err = parser.parse(input).expect_err(input);
+            assert_eq!(err.span.map(|s| s.line), Some(line), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_glob_patterns() {
+        let input = r#"gcc src/**/*.c "*.h" $(dir)/*.o x[ a?"[b]""#;
+        let got = ItemParser {
+            env: &Environment {
+                named: map! {"dir" => "out[1]"},
+                positional: vec![],
+            },
+        }
+        .parse(input)
+        .expect("parsing");
+        match got.as_slice() {
+            [Item::Pipeline { cmds, .. }] => assert_eq!(
+                cmds[0].globs,
+                vec![
+                    (0, "src/**/*.c".into()),
+                    (2, "out[[]1[]]/*.o".into()),
+                    (4, "a?[[]b[]]".into()),
+                ]
+            ),
+            items => panic!("unexpected items: {:?}", items),
+        }
+    }
+
+    #[test]
+    fn test_environment_variables() {
+        // Only read, since setting it would race with tests on other threads.
+        let path = std::env::var("PATH").expect("PATH is set");
+        let input = r#"export PATH=$(env:PATH)/bin GREETING="hello there"
+CC=$(cc) make $(env:RUN_TEST_MISSING:-all)"#;
+        let got = ItemParser {
+            env: &Environment {
+                named: map! {"cc" => "clang"},
+                positional: vec![],
+            },
+        }
+        .parse(input)
+        .expect("parsing");
+        match got.as_slice() {
+            [Item::Export(vars), Item::Pipeline { cmds, .. }] => {
+                assert_eq!(
+                    vars,
+                    &vec![
+                        ("PATH".to_owned(), format!("{}/bin", path)),
+                        ("GREETING".to_owned(), "hello there".to_owned()),
+                    ]
+                );
+                assert_eq!(
+                    cmds,
+                    &vec![Cmd {
+                        name: "make".into(),
+                        args: vec!["all".into()],
+                        env: vec![("CC".into(), "clang".into())],
+                        globs: vec![],
+                    }]
+                );
+                assert_eq!(cmds[0].to_string(), "CC=clang make all");
+            }
+            items => panic!("unexpected items: {:?}", items),
+        }
+
+        let parser = ItemParser {
+            env: &Environment::default(),
+        };
+        let got = parser.parse("env A=1 B=2 make all").expect("parsing");
+        match got.as_slice() {
+            [Item::Pipeline { cmds, .. }] => {
+                assert_eq!(cmds[0].to_string(), "A=1 B=2 make all")
+            }
+            items => panic!("unexpected items: {:?}", items),
+        }
+        for input in [
+            "export A=1 | cat",
+            "export A",
+            "true && export A=1",
+            "& export A=1",
+        ] {
+            assert!(parser.parse(input).is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_syntax_errors() {
+        let parser = ItemParser {
+            env: &Environment::default(),
+        };
+        for input in &[r#"echo "abc"#, "a | | b", "a >", "a > out | b", "echo $(x"] {
+            let err = parser.parse_at(input, 7).expect_err(input);
+            assert!(matches!(err.kind, ErrorKind::Parse(_)), "{}", input);
+            assert_eq!(err.span.map(|s| s.line), Some(7), "{}", input);
+        }
+    }
+
     #[test]
     fn test_file_redirection() {
         let input = r#"cat src/main.rs | rg match | head 5 > output.txt"#;
@@ -274,32 +1320,132 @@ mod tests {
             Cmd {
                 name: "cat".into(),
                 args: vec!["src/main.rs".into()],
+                env: vec![],
+                globs: vec![],
             },
             Cmd {
                 name: "rg".into(),
                 args: vec!["match".into()],
+                env: vec![],
+                globs: vec![],
             },
             Cmd {
                 name: "head".into(),
                 args: vec!["5".into()],
+                env: vec![],
+                globs: vec![],
             },
         ];
         let got = ItemParser {
             env: &Environment::default(),
         }
-        .parse(&input)
+        .parse(input)
         .expect("parsing");
         assert_eq!(
             got,
             vec![Item::Pipeline {
                 ignore_failure: false,
+                timeout: None,
+                retry: None,
                 terminus: Some("output.txt".into()),
+                append: false,
+                stderr: None,
+                stdin: None,
                 cmds: want,
-                literal: input.into()
+                literal: input.into(),
+                span: Span {
+                    line: 1,
+                    col: 1,
+                    len: input.len(),
+                },
+            }]
+        );
+    }
+
+    #[test]
+    fn test_append_and_input_redirection() {
+        let input = r#"sort < $(1).txt | uniq >> "sorted log.txt""#;
+        let got = ItemParser {
+            env: &Environment {
+                named: HashMap::new(),
+                positional: vec!["words".into()],
+            },
+        }
+        .parse(input)
+        .expect("parsing");
+        assert_eq!(
+            got,
+            vec![Item::Pipeline {
+                ignore_failure: false,
+                timeout: None,
+                retry: None,
+                terminus: Some("sorted log.txt".into()),
+                append: true,
+                stderr: None,
+                stdin: Some("words.txt".into()),
+                cmds: vec![
+                    Cmd {
+                        name: "sort".into(),
+                        args: vec![],
+                        env: vec![],
+                        globs: vec![],
+                    },
+                    Cmd {
+                        name: "uniq".into(),
+                        args: vec![],
+                        env: vec![],
+                        globs: vec![],
+                    },
+                ],
+                literal: input.into(),
+                span: Span {
+                    line: 1,
+                    col: 1,
+                    len: input.len(),
+                },
             }]
         );
     }
 
+    #[test]
+    fn test_stderr_redirection() {
+        let parser = ItemParser {
+            env: &Environment {
+                named: map! {"log" => "build"},
+                positional: vec![],
+            },
+        };
+        let cases = vec![
+            (
+                "cc 2> $(log).err",
+                None,
+                Some(Stderr::File("build.err".into())),
+            ),
+            ("cc 2>&1 | tee all.log", None, Some(Stderr::Stdout)),
+            (
+                "cc &> all.log",
+                Some("all.log".into()),
+                Some(Stderr::Stdout),
+            ),
+            (
+                "cc > out.log 2>err.log",
+                Some("out.log".into()),
+                Some(Stderr::File("err.log".into())),
+            ),
+        ];
+        for (input, want_terminus, want_stderr) in cases {
+            match parser.parse(input).expect(input).as_slice() {
+                [Item::Pipeline {
+                    terminus, stderr, ..
+                }] => {
+                    assert_eq!(terminus, &want_terminus, "{}", input);
+                    assert_eq!(stderr, &want_stderr, "{}", input);
+                }
+                items => panic!("{}: expected a single pipeline, got {:?}", input, items),
+            }
+        }
+    }
+
     #[test]
     fn test_skip_empty_lines() {
         let input = r#"
@@ -315,40 +1461,259 @@ mod tests {
         let want = vec![
             Item::Pipeline {
                 ignore_failure: false,
+                timeout: None,
+                retry: None,
                 terminus: None,
+                append: false,
+                stderr: None,
+                stdin: None,
                 cmds: vec![Cmd {
                     name: "one".into(),
                     args: vec![],
+                    env: vec![],
+                    globs: vec![],
                 }],
                 literal: "one".into(),
+                span: Span {
+                    line: 3,
+                    col: 9,
+                    len: 3,
+                },
             },
             Item::Pipeline {
                 ignore_failure: false,
+                timeout: None,
+                retry: None,
                 terminus: None,
+                append: false,
+                stderr: None,
+                stdin: None,
                 cmds: vec![Cmd {
                     name: "two".into(),
                     args: vec![],
+                    env: vec![],
+                    globs: vec![],
                 }],
                 literal: "two".into(),
+                span: Span {
+                    line: 6,
+                    col: 9,
+                    len: 3,
+                },
             },
             Item::Pipeline {
                 ignore_failure: false,
+                timeout: None,
+                retry: None,
                 terminus: None,
+                append: false,
+                stderr: None,
+                stdin: None,
                 cmds: vec![Cmd {
                     name: "three".into(),
                     args: vec![],
+                    env: vec![],
+                    globs: vec![],
                 }],
                 literal: "three".into(),
+                span: Span {
+                    line: 8,
+                    col: 9,
+                    len: 5,
+                },
+            },
+        ];
+        let got = ItemParser {
+            env: &Environment::default(),
+        }
+        .parse(input)
+        .expect("parsing");
+        assert_eq!(got, want);
+    }
+
+    #[test]
+    fn test_parallel_group() {
+        let input = "& one\n& - two\nthree\n& four";
+        let pipeline = |literal: &str, line, col| Item::Pipeline {
+            ignore_failure: literal.starts_with("- "),
+            timeout: None,
+            retry: None,
+            terminus: None,
+            append: false,
+            stderr: None,
+            stdin: None,
+            cmds: vec![Cmd {
+                name: literal.trim_start_matches("- ").into(),
+                args: vec![],
+                env: vec![],
+                globs: vec![],
+            }],
+            literal: literal.into(),
+            span: Span {
+                line,
+                col,
+                len: literal.len(),
             },
+        };
+        let want = vec![
+            Item::Parallel(vec![pipeline("one", 1, 3), pipeline("- two", 2, 3)]),
+            pipeline("three", 3, 1),
+            Item::Parallel(vec![pipeline("four", 4, 3)]),
         ];
         let got = ItemParser {
             env: &Environment::default(),
         }
-        .parse(&input)
+        .parse(input)
         .expect("parsing");
         assert_eq!(got, want);
     }
 
+    #[test]
+    fn test_timeout() {
+        let parser = ItemParser {
+            env: &Environment::default(),
+        };
+        let got = parser
+            .parse("- timeout 1.5s cargo test | tee log.txt")
+            .expect("parsing");
+        match got.as_slice() {
+            [Item::Pipeline {
+                cmds,
+                timeout,
+                ignore_failure,
+                ..
+            }] => {
+                assert_eq!(*timeout, Some(Duration::from_millis(1500)));
+                assert!(ignore_failure);
+                assert_eq!(cmds[0].to_string(), "cargo test");
+            }
+            got => panic!("unexpected items: {:?}", got),
+        }
+        assert_eq!(got[0].label().as_deref(), Some("cargo test | tee log.txt"));
+
+        for (input, want) in [
+            ("timeout 5s", "timeout expects a duration and a command"),
+            ("timeout soon make", "invalid duration: soon"),
+            ("timeout 5s cd src", "cd can't be given a timeout"),
+        ] {
+            let err = parser.parse(input).expect_err(input);
+            assert_eq!(err.to_string(), want);
+        }
+    }
+
+    #[test]
+    fn test_retry() {
+        let parser = ItemParser {
+            env: &Environment::default(),
+        };
+        let retry = |input: &str| match parser.parse(input).expect("parsing").as_slice() {
+            [Item::Pipeline {
+                cmds,
+                retry,
+                timeout,
+                ..
+            }] => (
+                retry.expect("retry"),
+                timeout.is_some(),
+                cmds[0].to_string(),
+            ),
+            got => panic!("unexpected items: {:?}", got),
+        };
+        let attempts = |attempts, backoff| Retry {
+            attempts,
+            backoff: Duration::from_millis(backoff),
+        };
+
+        assert_eq!(
+            retry("retry 3 make"),
+            (attempts(3, 0), false, "make ".into())
+        );
+        assert_eq!(
+            retry("retry 2 500ms timeout 1m make test"),
+            (attempts(2, 500), true, "make test".into())
+        );
+        assert_eq!(
+            retry("timeout 1m retry 2 1 ls"),
+            (attempts(2, 0), true, "1 ls".into())
+        );
+        let got = parser
+            .parse("- retry 2 500ms timeout 1m make test")
+            .expect("parsing");
+        assert_eq!(got[0].label().as_deref(), Some("make test"));
+
+        for (input, want) in [
+            (
+                "retry 3",
+                "retry expects a number of attempts and a command",
+            ),
+            ("retry 3 1s", "retry expects a command"),
+            ("retry 0 make", "invalid number of attempts: 0"),
+            ("retry 3 cd src", "cd can't be retried"),
+        ] {
+            let err = parser.parse(input).expect_err(input);
+            assert_eq!(err.to_string(), want);
+        }
+    }
+
+    #[test]
+    fn test_let() {
+        let env = Environment {
+            named: map! { "dir" => "src" },
+            positional: vec![],
+        };
+        let parser = ItemParser { env: &env };
+        let got = parser
+            .parse("let rev = git rev-parse HEAD\ncd $(dir)\n- echo $(rev) | tee $(dir).txt")
+            .expect("parsing");
+        let deferred = match got.as_slice() {
+            [Item::Let { name, pipeline }, Item::Chdir(..), Item::Deferred(deferred)] => {
+                assert_eq!(name, "rev");
+                assert_eq!(
+                    pipeline.label().as_deref(),
+                    Some("let rev = git rev-parse HEAD")
+                );
+                deferred
+            }
+            got => panic!("unexpected items: {:?}", got),
+        };
+        assert_eq!(deferred.line, 3);
+        assert!(deferred.ignore_failure);
+
+        let captured = map! { "rev" => "abc123" };
+        match deferred.resolve(&captured).expect("resolving").as_slice() {
+            [Item::Pipeline { cmds, .. }] => {
+                assert_eq!(cmds[0].to_string(), "echo abc123");
+                assert_eq!(cmds[1].to_string(), "tee src.txt");
+            }
+            got => panic!("unexpected items: {:?}", got),
+        }
+
+        for (input, want) in [
+            (
+                "echo $(rev)\nlet rev = true",
+                "no value specified for argument: rev",
+            ),
+            ("let rev", "expected let NAME = command"),
+            ("let 1 = true", "let expects a variable name"),
+            (
+                "let rev = true > rev.txt",
+                "let captures output, so it can't be redirected",
+            ),
+            ("let rev = cd src", "let can't capture cd"),
+            (
+                "let rev = true\ninputs $(rev)",
+                "inputs can't use variables captured by let",
+            ),
+            (
+                "let rev = true\n& let sha = echo $(rev)",
+                "let can't run concurrently",
+            ),
+        ] {
+            let err = parser.parse(input).expect_err(input);
+            assert_eq!(err.to_string(), want);
+        }
+    }
+
     #[test]
     fn test_ignore_failure() {
         let input = r#"- cat src/main.rs | rg match | head 5 > output.txt"#;
@@ -356,29 +1721,54 @@ mod tests {
             Cmd {
                 name: "cat".into(),
                 args: vec!["src/main.rs".into()],
+                env: vec![],
+                globs: vec![],
             },
             Cmd {
                 name: "rg".into(),
                 args: vec!["match".into()],
+                env: vec![],
+                globs: vec![],
             },
             Cmd {
                 name: "head".into(),
                 args: vec!["5".into()],
+                env: vec![],
+                globs: vec![],
             },
         ];
         let got = ItemParser {
             env: &Environment::default(),
         }
-        .parse(&input)
+        .parse(input)
         .expect("parsing");
         assert_eq!(
             got,
             vec![Item::Pipeline {
                 ignore_failure: true,
+                timeout: None,
+                retry: None,
                 terminus: Some("output.txt".into()),
+                append: false,
+                stderr: None,
+                stdin: None,
                 cmds: want,
-                literal: input.into()
+                literal: input.into(),
+                span: Span {
+                    line: 1,
+                    col: 1,
+                    len: input.len(),
+                },
             }]
         );
+
+        // Statements have nothing to fall back to if they fail.
+        let err = ItemParser {
+            env: &Environment::default(),
+        }
+        .parse("- cd missing")
+        .expect_err("ignoring cd");
+        assert_eq!(err.to_string(), "cd can't be ignored if it fails");
+        assert_eq!(err.span.map(|s| s.col), Some(3));
     }
 }
@@ -1,114 +1,1190 @@
-use crate::parser::{Cmd, Item};
-use glob::glob;
-use std::error::Error;
-use std::fs::File;
-use std::io::Write;
+use crate::ast::Op;
+use crate::builtin;
+use crate::config::NoMatch;
+use crate::error::{Error, ErrorKind};
+use crate::incremental;
+use crate::parser::{Chdir, Cmd, Item, Retry, Stderr};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf, MAIN_SEPARATOR};
 use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 // Pipeline can arbitrarily execute, writing to `output` and reporting any
 // errors it encounters.
 pub trait Pipeline<Out>
 where
-    Out: Write,
+    Out: Write + Send,
 {
-    fn execute(&self, output: Out) -> Result<(), Box<dyn Error>>;
+    fn execute(&self, ctx: &Context, output: Out) -> Result<(), Error>;
+}
+
+// Context holds the state an item executes within.
+#[derive(Clone, Debug, Default)]
+pub struct Context {
+    // Maximum number of concurrent jobs in a parallel group, unlimited if None.
+    pub jobs: Option<usize>,
+    // Prefixes every line of output, so that concurrent jobs stay readable.
+    pub label: Option<String>,
+    // Environment variables exported so far, set for every command.
+    pub env: Vec<(String, String)>,
+    // Directory commands run in, the process's own if None.
+    pub dir: Option<PathBuf>,
+    // Directories to return to, most recently pushed last.
+    pub dirs: Vec<Option<PathBuf>>,
+    // What to do with a glob pattern that matches nothing.
+    pub no_match: NoMatch,
+    // Run incremental items even when they're up to date.
+    pub force: bool,
+    // File recording the hashes of incremental items' inputs, if checking
+    // those rather than modification times.
+    pub cache: Option<PathBuf>,
+    // How long each pipeline may run for, unless it sets its own timeout.
+    pub timeout: Option<Duration>,
+    // Variables captured by let so far, for resolving the lines using them.
+    pub captured: HashMap<String, String>,
+    pub cancel: Cancel,
+}
+
+impl Context {
+    // Resolve a path against the directory commands run in.
+    pub fn path(&self, path: impl AsRef<Path>) -> PathBuf {
+        match &self.dir {
+            Some(dir) => dir.join(path),
+            None => path.as_ref().to_owned(),
+        }
+    }
+
+    // Paths matching the glob `pattern`, resolved against the directory
+    // commands run in but returned as written, ie relative if it was.
+    // Paths that can't be read are skipped, like a shell.
+    pub fn glob(&self, pattern: &str) -> Result<Vec<PathBuf>, glob::PatternError> {
+        let paths = glob::glob(&self.pattern(pattern))?.filter_map(Result::ok);
+        Ok(match &self.dir {
+            Some(dir) if Path::new(pattern).is_relative() => paths
+                .map(|path| path.strip_prefix(dir).map(Path::to_owned).unwrap_or(path))
+                .collect(),
+            _ => paths.collect(),
+        })
+    }
+
+    // The glob `pattern` resolved against the directory commands run in.
+    pub fn pattern(&self, pattern: &str) -> String {
+        match &self.dir {
+            Some(dir) if Path::new(pattern).is_relative() => format!(
+                "{}{}{}",
+                glob::Pattern::escape(&dir.to_string_lossy()),
+                MAIN_SEPARATOR,
+                pattern
+            ),
+            _ => pattern.to_owned(),
+        }
+    }
+
+    // Run items in sequence, applying statements to the context as they're
+    // reached. Errors are attributed to their source via `located`, failures
+    // that are ignored being reported and skipped.
+    pub fn run(&mut self, items: Vec<Item>, located: &dyn Fn(Error) -> Error) -> Result<(), Error> {
+        for item in items {
+            if self.cancel.is_cancelled() {
+                return Err(Error::new(ErrorKind::Cancelled));
+            }
+            match item {
+                Item::Comment(comment) => {
+                    println!("{}", comment);
+                }
+                Item::Export(vars) => {
+                    self.env.extend(vars);
+                }
+                Item::Chdir(change, span) => {
+                    self.chdir(&change, true)
+                        .map_err(|err| located(err.at(span)))?;
+                }
+                Item::Incremental {
+                    inputs,
+                    outputs,
+                    items,
+                } => {
+                    let labels: Vec<String> = items.iter().filter_map(Item::label).collect();
+                    if incremental::up_to_date(self, &inputs, &outputs, &labels).map_err(located)? {
+                        println!("// up to date: {}", labels.join("; "));
+                        continue;
+                    }
+                    self.run(items, located)?;
+                    incremental::record(self, &inputs, &labels).map_err(located)?;
+                }
+                Item::Inputs(_) | Item::Outputs(_) => {}
+                // Errors in the included file are reported against it.
+                // Its variables are its own, so what it captures is too.
+                Item::Include(include) => {
+                    let (file, text) = (&include.file, &include.text);
+                    let captured = std::mem::take(&mut self.captured);
+                    let result = self.run(include.items, &|err| err.with_source(file, text));
+                    self.captured = captured;
+                    result.map_err(located)?;
+                }
+                // A failure that's ignored captures nothing.
+                Item::Let { name, pipeline } => {
+                    let value = match pipeline.capture(self) {
+                        Ok(value) => value,
+                        Err(err) if pipeline.ignore_failure() => {
+                            eprintln!("{}", located(err).report());
+                            String::new()
+                        }
+                        Err(err) => return Err(located(err)),
+                    };
+                    self.captured.insert(name, value);
+                }
+                Item::Deferred(deferred) => {
+                    let items = deferred.resolve(&self.captured).map_err(located)?;
+                    self.run(items, located)?;
+                }
+                Item::Pipeline { .. } | Item::Parallel(_) | Item::Chain { .. } => {
+                    if let Err(err) = item.execute(self, io::stdout()) {
+                        if !item.ignore_failure() {
+                            return Err(located(err));
+                        }
+                        eprintln!("{}", located(err).report());
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Change the directory later items run in.
+    // Dry runs don't `check` that the directory exists, since an earlier
+    // command would have created it.
+    pub fn chdir(&mut self, change: &Chdir, check: bool) -> Result<(), Error> {
+        let dir = match change {
+            Chdir::Cd(dir) | Chdir::Pushd(dir) => Some(self.path(dir)),
+            Chdir::Popd => self
+                .dirs
+                .pop()
+                .ok_or_else(|| Error::builtin("popd", "directory stack is empty"))?,
+        };
+        if let (true, Some(dir)) = (check, &dir) {
+            if !dir.is_dir() {
+                let name = match change {
+                    Chdir::Pushd(_) => "pushd",
+                    _ => "cd",
+                };
+                return Err(Error::builtin(
+                    name,
+                    format!("no such directory: {}", dir.display()),
+                ));
+            }
+        }
+        if let Chdir::Pushd(_) = change {
+            self.dirs.push(self.dir.take());
+        }
+        self.dir = dir;
+        Ok(())
+    }
+}
+
+// Cancel signals running children to be killed.
+// Cancelling a token also cancels every token derived from it via `child`.
+#[derive(Clone, Debug, Default)]
+pub struct Cancel {
+    flag: Arc<AtomicBool>,
+    parent: Option<Box<Cancel>>,
+}
+
+impl Cancel {
+    pub fn child(&self) -> Cancel {
+        Cancel {
+            flag: Arc::default(),
+            parent: Some(Box::new(self.clone())),
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.flag.load(Ordering::SeqCst) || self.parent.as_ref().is_some_and(|p| p.is_cancelled())
+    }
+
+    // Sleep for `duration`, in slices so that being cancelled cuts it short.
+    pub fn sleep(&self, duration: Duration) -> Result<(), Error> {
+        let start = Instant::now();
+        while start.elapsed() < duration {
+            if self.is_cancelled() {
+                return Err(Error::new(ErrorKind::Cancelled));
+            }
+            let left = duration.saturating_sub(start.elapsed());
+            thread::sleep(left.min(Duration::from_millis(10)));
+        }
+        Ok(())
+    }
 }
 
 impl<Out> Pipeline<Out> for Item
 where
-    Out: Write,
+    Out: Write + Send,
 {
-    fn execute(&self, mut output: Out) -> Result<(), Box<dyn Error>> {
-        if let Item::Pipeline { cmds, terminus, .. } = self {
-            let mut prev = None;
-            let mut cmds = cmds.into_iter().peekable();
-
-            while let Some(cmd) = cmds.next() {
-                write!(output, "{}\n", &cmd)?;
-                let Cmd { name, args } = cmd;
-
-                match name.as_ref() {
-                    // Note(jfm):
-                    //  Should builtins get access to pipes? Do they need them?
-                    //  Should we check to see if an "rm" utility exists on the machine?
-                    //  User would probably like to use their installed rm utitliy.
-                    "rm" => {
-                        args.iter()
-                            .map(|arg| rm(arg))
-                            .collect::<Result<Vec<_>, _>>()
-                            .map_err(|e| format!("rm {}: {}", args.join(" "), e))?;
-                    }
-                    "cp" => {
-                        let mut args = args.into_iter();
-                        let (src, dst) = (args.next(), args.next());
-                        match (src, dst) {
-                            (Some(src), Some(dst)) => {
-                                cp(src, dst).map_err(|e| format!("cp {} {}: {}", src, dst, e))?;
+    fn execute(&self, ctx: &Context, mut output: Out) -> Result<(), Error> {
+        match self {
+            // Statements change the context later items run in, so they're
+            // applied by whatever runs items in sequence.
+            Item::Comment(_)
+            | Item::Export(_)
+            | Item::Chdir(..)
+            | Item::Include(_)
+            | Item::Inputs(_)
+            | Item::Outputs(_)
+            | Item::Incremental { .. }
+            | Item::Let { .. } => Ok(()),
+            Item::Pipeline { .. } => self.attempt(ctx, &mut output, false).map(|_| ()),
+            Item::Deferred(deferred) => {
+                // Type-erased for the same reason as chains below.
+                let output: &mut (dyn Write + Send) = &mut output;
+                for item in deferred.resolve(&ctx.captured)? {
+                    item.execute(ctx, &mut *output)?;
+                }
+                Ok(())
+            }
+            Item::Parallel(items) => {
+                // Siblings get their own token so that a failure cancels the
+                // group without cancelling whatever the group belongs to.
+                let cancel = ctx.cancel.child();
+                let queue = Mutex::new(items.iter());
+                let failure: Mutex<Option<Error>> = Mutex::new(None);
+                let output: Mutex<&mut (dyn Write + Send)> = Mutex::new(&mut output);
+
+                thread::scope(|s| {
+                    for _ in 0..ctx.jobs.unwrap_or(items.len()).min(items.len()) {
+                        s.spawn(|| loop {
+                            if cancel.is_cancelled() {
+                                break;
                             }
-                            _ => {
-                                return Err(
-                                    format!("cp: invalid arguments: {:?} {:?}", src, dst).into()
-                                );
+                            let item = match queue.lock().expect("job queue").next() {
+                                Some(item) => item,
+                                None => break,
+                            };
+                            let ctx = Context {
+                                label: item.label(),
+                                cancel: cancel.clone(),
+                                ..ctx.clone()
+                            };
+                            if let Err(err) = item.execute(&ctx, Shared(&output)) {
+                                if item.ignore_failure() {
+                                    eprintln!(
+                                        "[{}] error: {}",
+                                        item.label().unwrap_or_default(),
+                                        err
+                                    );
+                                    continue;
+                                }
+                                // Only the first failure is interesting, the
+                                // rest are likely the siblings being cancelled.
+                                let mut failure = failure.lock().expect("job failure");
+                                if failure.is_none() {
+                                    *failure = Some(err);
+                                }
+                                cancel.cancel();
                             }
-                        };
-                    }
-                    _ => {
-                        let stdin = prev.map_or(Stdio::inherit(), |output: Child| {
-                            Stdio::from(output.stdout.unwrap())
                         });
+                    }
+                });
 
-                        let stdout = if cmds.peek().is_some() {
-                            Stdio::piped()
-                        } else {
-                            if let Some(terminus) = &terminus {
-                                File::create(terminus)
-                                    .map_err(|e| format!("opening terminus file: {}", e))?
-                                    .into()
-                            } else {
-                                Stdio::inherit()
-                            }
-                        };
-
-                        let output = Command::new(&name)
-                            .current_dir(std::env::current_dir().map_err(|e| {
-                                format!("fetching current working directory: {}", e)
-                            })?)
-                            .args(args)
-                            .stdin(stdin)
-                            .stdout(stdout)
-                            .spawn()
-                            .map_err(|e| format!("{}: {}", &name, e))?;
-
-                        prev = Some(output);
+                match failure.into_inner().expect("job failure") {
+                    Some(err) => Err(err),
+                    None => Ok(()),
+                }
+            }
+            Item::Chain { first, rest } => {
+                // Type-erased so that nested items don't instantiate execute
+                // for ever-deeper references.
+                let output: &mut (dyn Write + Send) = &mut output;
+                // Like a shell, the chain's status is that of the last item
+                // that ran.
+                let mut result = first.execute(ctx, &mut *output);
+                for (op, item) in rest {
+                    match (&result, op) {
+                        (Err(err), _) if matches!(err.kind, ErrorKind::Cancelled) => break,
+                        (Ok(()), Op::And) | (Err(_), Op::Or) => {
+                            result = item.execute(ctx, &mut *output);
+                        }
+                        _ => {}
                     }
-                };
+                }
+                result
             }
+        }
+    }
+}
+
+impl Item {
+    // Run a pipeline item, returning what it writes to stdout, trimmed,
+    // rather than passing it on.
+    pub fn capture(&self, ctx: &Context) -> Result<String, Error> {
+        let output = self.attempt(ctx, &mut io::stdout(), true)?;
+        Ok(String::from_utf8_lossy(&output).trim().to_owned())
+    }
 
-            if let Some(mut finish) = prev {
-                finish.wait().ok();
+    // Run a pipeline item, as many times as it may be retried if it fails,
+    // returning its stdout if asked to `capture` it.
+    fn attempt<Out: Write>(
+        &self,
+        ctx: &Context,
+        output: &mut Out,
+        capture: bool,
+    ) -> Result<Vec<u8>, Error> {
+        let (cmds, redirects, timeout, retry, span) = match self {
+            Item::Pipeline {
+                cmds,
+                terminus,
+                append,
+                stderr,
+                stdin,
+                timeout,
+                retry,
+                span,
+                ..
+            } => (
+                cmds,
+                Redirects {
+                    terminus: terminus.as_ref(),
+                    append: *append,
+                    stderr: stderr.as_ref(),
+                    stdin: stdin.as_ref(),
+                    capture,
+                },
+                timeout.or(ctx.timeout),
+                retry.unwrap_or(Retry {
+                    attempts: 1,
+                    backoff: Duration::ZERO,
+                }),
+                *span,
+            ),
+            _ => return Ok(vec![]),
+        };
+        let (mut attempt, mut backoff) = (1, retry.backoff);
+        loop {
+            let err = match pipeline(ctx, output, cmds, redirects, timeout) {
+                Ok(captured) => return Ok(captured),
+                Err(err) => err.at(span),
+            };
+            if attempt >= retry.attempts || matches!(err.kind, ErrorKind::Cancelled) {
+                return Err(err);
             }
+            let mut note = format!(
+                "// attempt {} of {} failed ({})",
+                attempt, retry.attempts, err
+            );
+            if !backoff.is_zero() {
+                note.push_str(&format!(", retrying in {:?}", backoff));
+            }
+            let line = match &ctx.label {
+                Some(label) => format!("[{}] {}\n", label, note),
+                None => format!("{}\n", note),
+            };
+            output.write_all(line.as_bytes())?;
+            ctx.cancel.sleep(backoff).map_err(|e| e.at(span))?;
+            attempt += 1;
+            backoff *= 2;
         }
+    }
+}
 
+// Redirections of a pipeline's input and output.
+#[derive(Clone, Copy)]
+struct Redirects<'a> {
+    terminus: Option<&'a PathBuf>,
+    append: bool,
+    stderr: Option<&'a Stderr>,
+    stdin: Option<&'a PathBuf>,
+    // Collect stdout of the last stage rather than passing it on.
+    capture: bool,
+}
+
+// A running stage of a pipeline.
+enum Stage {
+    Child(Child),
+    // A builtin running on its own thread, taken once it has finished.
+    Builtin(Option<thread::JoinHandle<Result<(), Error>>>),
+}
+
+// Where a stage's stdout goes.
+enum Sink {
+    Pipe(io::PipeWriter),
+    File(File),
+    Inherit,
+}
+
+// Start each command in the pipeline, connecting stdout of each into stdin of
+// the next, then wait for them all, killing them if they take longer than
+// `timeout`. Returns the pipeline's stdout if it's captured.
+fn pipeline<Out>(
+    ctx: &Context,
+    output: &mut Out,
+    cmds: &[Cmd],
+    redirects: Redirects,
+    timeout: Option<Duration>,
+) -> Result<Vec<u8>, Error>
+where
+    Out: Write,
+{
+    // The pipeline gets a token of its own so that timing out stops its
+    // builtins without cancelling anything else.
+    let ctx = &Context {
+        cancel: ctx.cancel.child(),
+        ..ctx.clone()
+    };
+    // Every stage along with the name it was started as, so that each can be
+    // waited on and reported.
+    let mut stages: Vec<(&str, Stage)> = vec![];
+    // Threads forwarding labelled output, joined once stages finish.
+    let mut forwarders = vec![];
+    // Thread collecting the last stage's stdout, if it's captured.
+    let mut captured = None;
+    // Stdin of the next stage, the read end of the pipe from the previous one.
+    let mut prev: Option<io::PipeReader> = None;
+    let mut cmds = cmds.iter().peekable();
+
+    // Files are opened once, up front, so that every stage shares them.
+    let terminus = match redirects.terminus.map(|path| ctx.path(path)) {
+        Some(path) => Some(
+            OpenOptions::new()
+                .write(true)
+                .create(true)
+                .append(redirects.append)
+                .truncate(!redirects.append)
+                .open(&path)
+                .map_err(|e| Error::io(format!("opening {}", path.display()), e))?,
+        ),
+        None => None,
+    };
+    let stderr_file = match redirects.stderr {
+        Some(Stderr::File(path)) => Some(
+            File::create(ctx.path(path))
+                .map_err(|e| Error::io(format!("opening {}", path.display()), e))?,
+        ),
+        _ => None,
+    };
+    let mut stdin_file = match redirects.stdin {
+        Some(path) => Some(
+            File::open(ctx.path(path))
+                .map_err(|e| Error::io(format!("opening {}", path.display()), e))?,
+        ),
+        None => None,
+    };
+
+    // Stages that started before one fails to are stopped rather than left
+    // running unattended.
+    let mut start = || -> Result<(), Error> {
+        while let Some(cmd) = cmds.next() {
+            // Written in one go so that concurrent jobs don't interleave.
+            let line = match &ctx.label {
+                Some(label) => format!("[{}] {}\n", label, &cmd),
+                None => format!("{}\n", &cmd),
+            };
+            output.write_all(line.as_bytes())?;
+            let Cmd { name, env, .. } = cmd;
+
+            // Only the first stage can read the input file, later ones read the
+            // pipe from the stage before.
+            let stdin = prev.take();
+            let stdin_file = stdin_file.take();
+            let sink = if cmds.peek().is_some() {
+                let (reader, writer) = io::pipe().map_err(|e| Error::io("creating pipe", e))?;
+                prev = Some(reader);
+                Sink::Pipe(writer)
+            } else if redirects.capture {
+                let (mut reader, writer) = io::pipe().map_err(|e| Error::io("creating pipe", e))?;
+                captured = Some(thread::spawn(move || {
+                    let mut buf = vec![];
+                    reader.read_to_end(&mut buf).map(|_| buf)
+                }));
+                Sink::Pipe(writer)
+            } else if let Some(terminus) = &terminus {
+                Sink::File(terminus.try_clone().map_err(duplicating)?)
+            } else if let Some(label) = &ctx.label {
+                let (reader, writer) = io::pipe().map_err(|e| Error::io("creating pipe", e))?;
+                forwarders.push(forward(label, reader, io::stdout));
+                Sink::Pipe(writer)
+            } else {
+                Sink::Inherit
+            };
+
+            if builtin::runs(ctx, cmd) {
+                let mut stdin: Box<dyn Read + Send> = match (stdin, stdin_file) {
+                    (Some(pipe), _) => Box::new(pipe),
+                    (None, Some(file)) => Box::new(file),
+                    (None, None) => Box::new(io::stdin()),
+                };
+                let mut stdout: Box<dyn Write + Send> = match sink {
+                    Sink::Pipe(writer) => Box::new(writer),
+                    Sink::File(file) => Box::new(file),
+                    Sink::Inherit => Box::new(io::stdout()),
+                };
+                // Globs are expanded like for any other command.
+                let mut cmd = cmd.clone();
+                cmd.args = expand(ctx, &cmd)?;
+                cmd.globs.clear();
+                // Run on a thread so that it streams alongside the other stages,
+                // closing its end of the pipe once it's done.
+                let ctx = ctx.clone();
+                let handle =
+                    thread::spawn(move || builtin::run(&ctx, &cmd, &mut stdin, &mut stdout));
+                stages.push((name, Stage::Builtin(Some(handle))));
+                continue;
+            }
+
+            let stdin = match (stdin, stdin_file) {
+                (Some(pipe), _) => Stdio::from(pipe),
+                (None, Some(file)) => Stdio::from(file),
+                (None, None) => Stdio::inherit(),
+            };
+            // Stdout, along with a duplicate of it for merging stderr into.
+            let (stdout, merged) = match sink {
+                Sink::Pipe(writer) => (
+                    Stdio::from(writer.try_clone().map_err(duplicating)?),
+                    Stdio::from(writer),
+                ),
+                Sink::File(file) => (
+                    Stdio::from(file.try_clone().map_err(duplicating)?),
+                    Stdio::from(file),
+                ),
+                Sink::Inherit => (Stdio::inherit(), Stdio::from(io::stdout())),
+            };
+
+            let stderr = match (redirects.stderr, &stderr_file) {
+                (Some(Stderr::Stdout), _) => merged,
+                (_, Some(file)) => Stdio::from(file.try_clone().map_err(duplicating)?),
+                _ => match &ctx.label {
+                    Some(label) => {
+                        let (reader, writer) =
+                            io::pipe().map_err(|e| Error::io("creating pipe", e))?;
+                        forwarders.push(forward(label, reader, io::stderr));
+                        Stdio::from(writer)
+                    }
+                    None => Stdio::inherit(),
+                },
+            };
+
+            let mut command = Command::new(name);
+            if let Some(dir) = &ctx.dir {
+                command.current_dir(dir);
+            }
+            // A pipeline that may time out starts each stage in a process
+            // group of its own, so that whatever the stage starts in turn is
+            // killed with it. Others stay in ours, where they can read the
+            // terminal and are interrupted along with run.
+            #[cfg(unix)]
+            if timeout.is_some() {
+                use std::os::unix::process::CommandExt;
+                command.process_group(0);
+            }
+            let child = command
+                .args(expand(ctx, cmd)?)
+                .envs(ctx.env.iter().chain(env).map(|(k, v)| (k, v)))
+                .stdin(stdin)
+                .stdout(stdout)
+                .stderr(stderr)
+                .spawn()
+                .map_err(|source| {
+                    Error::new(ErrorKind::Spawn {
+                        cmd: name.clone(),
+                        source,
+                    })
+                })?;
+
+            stages.push((name, Stage::Child(child)));
+        }
         Ok(())
+    };
+    if let Err(err) = start() {
+        ctx.cancel.cancel();
+        stop(&mut stages);
+        return Err(err);
+    }
+
+    let result = wait(stages, &ctx.cancel, timeout);
+    // A killed child's own children may still hold its pipes open,
+    // so don't hang around for output from a cancelled pipeline.
+    if !ctx.cancel.is_cancelled() {
+        for forwarder in forwarders {
+            forwarder.join().ok();
+        }
+    }
+    result?;
+    match captured {
+        Some(reader) => reader
+            .join()
+            .expect("capturing output")
+            .map_err(|e| Error::io("capturing output", e)),
+        None => Ok(vec![]),
     }
 }
 
-// rm the given glob pattern.
-// Does what you expect: removes the files that match the pattern.
-//
-// TODO: Handle powershell path expansions eg
-//  "$Env:UserProfile" -> C:\Users\<user>
+// Wait on every stage, not just the last, so that none is left behind and a
+// failure anywhere in the pipeline is noticed.
+// Like "pipefail", the rightmost failing stage is reported.
 //
-fn rm(pattern: &str) -> Result<(), Box<dyn Error>> {
-    glob(pattern)?
-        .collect::<Result<Vec<_>, _>>()?
+// Stages are polled rather than blocked on so that children can be killed if
+// the pipeline is cancelled or times out. Builtins notice cancellation, or
+// their pipes closing, on their own.
+fn wait(
+    mut stages: Vec<(&str, Stage)>,
+    cancel: &Cancel,
+    timeout: Option<Duration>,
+) -> Result<(), Error> {
+    let started = Instant::now();
+    let mut results: Vec<Option<Result<(), Error>>> = stages.iter().map(|_| None).collect();
+
+    while results.iter().any(Option::is_none) {
+        let expired = timeout.filter(|timeout| started.elapsed() >= *timeout);
+        if cancel.is_cancelled() || expired.is_some() {
+            cancel.cancel();
+            stop(&mut stages);
+            return Err(Error::new(match expired {
+                // The stages still running are the ones holding things up.
+                Some(after) => ErrorKind::Timeout {
+                    cmd: stages
+                        .iter()
+                        .zip(&results)
+                        .filter(|(_, result)| result.is_none())
+                        .map(|((name, _), _)| *name)
+                        .collect::<Vec<_>>()
+                        .join(" | "),
+                    after,
+                },
+                None => ErrorKind::Cancelled,
+            }));
+        }
+        for ((name, stage), result) in stages.iter_mut().zip(results.iter_mut()) {
+            if result.is_some() {
+                continue;
+            }
+            *result = match stage {
+                Stage::Child(child) => child
+                    .try_wait()
+                    .map_err(|e| Error::io(format!("waiting on {}", name), e))?
+                    .map(|status| match status.success() {
+                        true => Ok(()),
+                        false => Err(Error::new(ErrorKind::Exit {
+                            cmd: name.to_string(),
+                            status,
+                        })),
+                    }),
+                Stage::Builtin(handle) if handle.as_ref().is_some_and(|h| h.is_finished()) => {
+                    handle.take().map(|handle| {
+                        handle
+                            .join()
+                            .unwrap_or_else(|_| Err(Error::builtin(*name, "panicked")))
+                    })
+                }
+                Stage::Builtin(_) => None,
+            };
+        }
+        thread::sleep(Duration::from_millis(5));
+    }
+
+    results
         .into_iter()
-        .map(|entry| std::fs::remove_file(&entry))
-        .collect::<Result<Vec<_>, _>>()?;
-    Ok(())
+        .rev()
+        .flatten()
+        .find_map(Result::err)
+        .map_or(Ok(()), Err)
+}
+
+// Kill and reap every child stage. Builtins stop by themselves once the
+// pipeline's token is cancelled, or their pipes close.
+fn stop(stages: &mut [(&str, Stage)]) {
+    for (_, stage) in stages {
+        if let Stage::Child(child) = stage {
+            kill_descendants(child);
+            child.kill().ok();
+            child.wait().ok();
+        }
+    }
 }
 
-fn cp(src: &str, dst: &str) -> Result<(), Box<dyn Error>> {
-    std::fs::copy(src, dst)?;
-    Ok(())
+// Kill the process group a child leads, if it leads one.
+#[cfg(unix)]
+fn kill_descendants(child: &Child) {
+    use std::convert::TryFrom;
+    extern "C" {
+        fn kill(pid: i32, sig: i32) -> i32;
+    }
+    const SIGKILL: i32 = 9;
+    if let Ok(pid) = i32::try_from(child.id()) {
+        // Safety: kill only reads its arguments. A child that isn't a group
+        // leader has no group with its id, so nothing else is signalled.
+        unsafe {
+            kill(-pid, SIGKILL);
+        }
+    }
+}
+
+// Kill the tree of processes a child started.
+#[cfg(windows)]
+fn kill_descendants(child: &Child) {
+    Command::new("taskkill")
+        .args(["/T", "/F", "/PID", &child.id().to_string()])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .ok();
+}
+
+// Arguments of a command, with glob patterns expanded into the
+// paths they match.
+// Unlike on Unix, no shell does this for programs on Windows.
+fn expand(ctx: &Context, cmd: &Cmd) -> Result<Vec<String>, Error> {
+    let mut args = vec![];
+    let mut globs = cmd.globs.iter().peekable();
+    for (index, arg) in cmd.args.iter().enumerate() {
+        let pattern = match globs.next_if(|(i, _)| *i == index) {
+            Some((_, pattern)) => pattern,
+            None => {
+                args.push(arg.clone());
+                continue;
+            }
+        };
+        let paths = ctx
+            .glob(pattern)
+            .map_err(|e| Error::builtin(&cmd.name, format!("invalid pattern {}: {}", arg, e)))?;
+        match (paths.is_empty(), ctx.no_match) {
+            (true, NoMatch::Keep) => args.push(arg.clone()),
+            (true, NoMatch::Error) => {
+                return Err(Error::builtin(&cmd.name, format!("no matches for {}", arg)));
+            }
+            (false, _) => args.extend(paths.iter().map(|p| p.to_string_lossy().into_owned())),
+        }
+    }
+    Ok(args)
+}
+
+// Forward each line read from `src` to the writer, prefixed by the label.
+fn forward<Src, Dst>(label: &str, src: Src, dst: fn() -> Dst) -> thread::JoinHandle<()>
+where
+    Src: Read + Send + 'static,
+    Dst: Write + 'static,
+{
+    let prefix = format!("[{}] ", label);
+    thread::spawn(move || {
+        let mut src = BufReader::new(src);
+        let mut line = vec![];
+        while let Ok(n) = src.read_until(b'\n', &mut line) {
+            if n == 0 {
+                break;
+            }
+            if !line.ends_with(b"\n") {
+                line.push(b'\n');
+            }
+            // Write the whole line at once so concurrent lines don't interleave.
+            let mut buf = prefix.clone().into_bytes();
+            buf.append(&mut line);
+            dst().write_all(&buf).ok();
+        }
+    })
+}
+
+// Shared lets concurrent jobs write to the same output.
+// The output is type erased so that nested groups don't recurse
+// infinitely when monomorphizing.
+struct Shared<'a, 'b>(&'a Mutex<&'b mut (dyn Write + Send)>);
+
+impl Write for Shared<'_, '_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().expect("shared output").write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.lock().expect("shared output").flush()
+    }
+}
+
+fn duplicating(err: io::Error) -> Error {
+    Error::io("duplicating handle", err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::env::Environment;
+    use crate::parser::ItemParser;
+    use crate::util::testing::TempDir;
+
+    fn run(line: &str) -> Result<(), Error> {
+        let items = ItemParser {
+            env: &Environment::default(),
+        }
+        .parse(line)?;
+        items[0].execute(&Context::default(), std::io::sink())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_exit_status() {
+        run("true").expect("successful command");
+
+        let err = run("sh -c \"exit 3\"").expect_err("failing command");
+        assert!(matches!(&err.kind, ErrorKind::Exit { cmd, .. } if cmd == "sh"));
+        assert_eq!(err.code(), 3);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_pipefail() {
+        let err = run("sh -c \"exit 2\" | cat").expect_err("failing stage");
+        assert!(matches!(&err.kind, ErrorKind::Exit { cmd, .. } if cmd == "sh"));
+        assert_eq!(err.code(), 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_spawn_failure() {
+        let dir = TempDir::new("spawn");
+        let items = ItemParser {
+            env: &Environment::default(),
+        }
+        .parse("sh -c \"sleep 0.3; touch done\" | run-no-such-program")
+        .expect("parsing");
+        let ctx = Context {
+            dir: Some(dir.clone()),
+            ..Context::default()
+        };
+        let err = items[0]
+            .execute(&ctx, std::io::sink())
+            .expect_err("spawning");
+        assert!(matches!(err.kind, ErrorKind::Spawn { .. }));
+        // The stage that did start was killed rather than left running.
+        thread::sleep(Duration::from_millis(600));
+        assert!(!dir.join("done").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_stderr_redirection() {
+        let dir = TempDir::new("stderr");
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        let read = |name: &str| std::fs::read_to_string(dir.join(name)).expect("reading output");
+        let noisy = r#"sh -c "echo out; echo err >&2""#;
+
+        run(&format!("{} 2> {}", noisy, path("err.log"))).expect("redirecting stderr");
+        assert_eq!(read("err.log"), "err\n");
+
+        run(&format!("{} 2>&1 | sort > {}", noisy, path("merged.log"))).expect("merging stderr");
+        assert_eq!(read("merged.log"), "err\nout\n");
+
+        run(&format!("{} &> {}", noisy, path("all.log"))).expect("redirecting both");
+        assert_eq!(read("all.log"), "out\nerr\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_append_and_input_redirection() {
+        let dir = TempDir::new("append");
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        std::fs::write(dir.join("in.txt"), "b\na\n").expect("writing input");
+
+        run(&format!("sort < {} > {}", path("in.txt"), path("out.txt"))).expect("sorting");
+        run(&format!("echo c >> {}", path("out.txt"))).expect("appending");
+        let got = std::fs::read_to_string(dir.join("out.txt")).expect("reading output");
+        assert_eq!(got, "a\nb\nc\n");
+
+        let err = run(&format!("cat < {}", path("missing.txt"))).expect_err("missing input");
+        assert!(matches!(err.kind, ErrorKind::Io { .. }));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_builtins_in_pipes() {
+        let dir = TempDir::new("pipes");
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        let read = |name: &str| std::fs::read_to_string(dir.join(name)).expect("reading output");
+
+        run(&format!(
+            "echo b a | tr \" \" \"\\n\" | sort > {}",
+            path("sorted.txt")
+        ))
+        .expect("builtin into commands");
+        assert_eq!(read("sorted.txt"), "a\nb\n");
+
+        run(&format!(
+            "sort < {} | cat | cat > {}",
+            path("sorted.txt"),
+            path("copy.txt")
+        ))
+        .expect("commands into builtins");
+        assert_eq!(read("copy.txt"), "a\nb\n");
+
+        std::fs::write(dir.join("big.txt"), "line\n".repeat(100_000)).expect("writing input");
+        run(&format!("cat {} | head -n 1", path("big.txt"))).expect("reader exits early");
+
+        let err = run(&format!("cat {} | sort", path("missing.txt"))).expect_err("failing builtin");
+        assert!(matches!(&err.kind, ErrorKind::Builtin { cmd, .. } if cmd == "cat"));
+
+        // Flags builtins don't support are left to the installed program.
+        run(&format!(
+            "cat -n {} > {}",
+            path("sorted.txt"),
+            path("numbered.txt")
+        ))
+        .expect("falling back to cat");
+        assert!(read("numbered.txt").contains("1\ta"));
+        run(&format!("env RUN_TEST=1 env > {}", path("env.txt"))).expect("env with a command");
+        assert!(read("env.txt").lines().any(|line| line == "RUN_TEST=1"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_glob_expansion() {
+        let dir = TempDir::new("glob");
+        std::fs::create_dir_all(dir.join("src/nested")).expect("creating dir");
+        for file in ["src/a.c", "src/nested/b.c", "src/c.h"] {
+            std::fs::write(dir.join(file), "").expect("writing input");
+        }
+        let items = ItemParser {
+            env: &Environment::default(),
+        }
+        .parse("sh -c \"echo $* > args.txt\" sh src/**/*.c \"*.c\" *.o")
+        .expect("parsing");
+        let mut ctx = Context {
+            dir: Some(dir.clone()),
+            ..Context::default()
+        };
+
+        items[0].execute(&ctx, std::io::sink()).expect("expanding");
+        let args = std::fs::read_to_string(dir.join("args.txt")).expect("reading output");
+        assert_eq!(args, "src/a.c src/nested/b.c *.c *.o\n");
+
+        ctx.no_match = NoMatch::Error;
+        let err = items[0]
+            .execute(&ctx, std::io::sink())
+            .expect_err("no matches");
+        assert_eq!(err.to_string(), "sh: no matches for *.o");
+
+        // Builtins see the expanded paths too.
+        let items = ItemParser {
+            env: &Environment::default(),
+        }
+        .parse("mkdir out\ncp src/*.c src/*.h out")
+        .expect("parsing");
+        ctx.run(items, &|err| err).expect("copying");
+        assert!(dir.join("out/a.c").is_file() && dir.join("out/c.h").is_file());
+
+        // Quoted patterns match literally, so only the file named "*" goes.
+        std::fs::write(dir.join("out/*"), "").expect("writing input");
+        let items = ItemParser {
+            env: &Environment::default(),
+        }
+        .parse("rm \"out/*\"\nrm out/*.h")
+        .expect("parsing");
+        ctx.run(items, &|err| err).expect("removing");
+        assert!(!dir.join("out/*").exists() && !dir.join("out/c.h").exists());
+        assert!(dir.join("out/a.c").is_file());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_chain() {
+        run("true && true").expect("both succeed");
+        run("false || true").expect("fallback succeeds");
+        run("true || sh -c \"exit 5\"").expect("fallback skipped");
+        run("false && sh -c \"exit 5\" || true").expect("skipped to fallback");
+
+        let err = run("sh -c \"exit 3\" && true").expect_err("first fails");
+        assert_eq!(err.code(), 3);
+        let err = run("true && false || sh -c \"exit 4\"").expect_err("fallback fails");
+        assert_eq!(err.code(), 4);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_timeout() {
+        let started = Instant::now();
+        let err = run("timeout 100ms sh -c \"sleep 10\" | cat").expect_err("timing out");
+        assert!(
+            matches!(&err.kind, ErrorKind::Timeout { cmd, .. } if cmd == "sh | cat"),
+            "{}",
+            err
+        );
+        assert_eq!(err.code(), 124);
+
+        let err = run("timeout 100ms sleep 10").expect_err("timing out builtin");
+        assert!(matches!(&err.kind, ErrorKind::Timeout { cmd, .. } if cmd == "sleep"));
+
+        let items = ItemParser {
+            env: &Environment::default(),
+        }
+        .parse("- sleep 10\nsleep 10")
+        .expect("parsing");
+        let mut ctx = Context {
+            timeout: Some(Duration::from_millis(100)),
+            ..Context::default()
+        };
+        let err = ctx.run(items, &|err| err).expect_err("timing out");
+        assert!(matches!(err.kind, ErrorKind::Timeout { .. }));
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        // Whatever the pipeline started is killed too.
+        let dir = TempDir::new("timeout");
+        let items = ItemParser {
+            env: &Environment::default(),
+        }
+        .parse("timeout 200ms sh -c \"(sleep 0.5; touch done) & wait\"")
+        .expect("parsing");
+        let ctx = Context {
+            dir: Some(dir.clone()),
+            ..Context::default()
+        };
+        items[0]
+            .execute(&ctx, std::io::sink())
+            .expect_err("timing out");
+        thread::sleep(Duration::from_millis(800));
+        assert!(!dir.join("done").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_retry() {
+        let dir = TempDir::new("retry");
+        // Fails until it's been run three times.
+        std::fs::write(
+            dir.join("flaky.sh"),
+            "echo x >> count; test `wc -l < count` -ge 3",
+        )
+        .expect("writing script");
+        let ctx = Context {
+            dir: Some(dir.clone()),
+            ..Context::default()
+        };
+        let execute = |line: &str| {
+            std::fs::remove_file(dir.join("count")).ok();
+            let items = ItemParser {
+                env: &Environment::default(),
+            }
+            .parse(line)
+            .expect("parsing");
+            let mut output = vec![];
+            let result = items[0].execute(&ctx, &mut output);
+            (result, String::from_utf8(output).expect("utf8 output"))
+        };
+
+        let (result, output) = execute("retry 3 10ms sh flaky.sh");
+        result.expect("succeeding on the last attempt");
+        assert_eq!(
+            output
+                .lines()
+                .filter(|line| line.starts_with("//"))
+                .collect::<Vec<_>>(),
+            vec![
+                "// attempt 1 of 3 failed (sh: exit status: 1), retrying in 10ms",
+                "// attempt 2 of 3 failed (sh: exit status: 1), retrying in 20ms",
+            ]
+        );
+
+        let (result, output) = execute("retry 2 sh flaky.sh");
+        let err = result.expect_err("failing every attempt");
+        assert!(matches!(&err.kind, ErrorKind::Exit { cmd, .. } if cmd == "sh"));
+        assert_eq!(output.matches("sh flaky.sh").count(), 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_capture() {
+        let dir = TempDir::new("capture");
+        let items = ItemParser {
+            env: &Environment::default(),
+        }
+        .parse(
+            "let who = echo \"  there  \" | tr a-z A-Z\n\
+             let greeting = sh -c \"echo hi\"\n\
+             echo $(greeting) $(who) > out.txt\n\
+             - let missing = sh -c \"echo partial; exit 1\"",
+        )
+        .expect("parsing");
+        let mut ctx = Context {
+            dir: Some(dir.clone()),
+            ..Context::default()
+        };
+        ctx.run(items, &|err| err).expect("running");
+
+        let got = std::fs::read_to_string(dir.join("out.txt")).expect("reading output");
+        assert_eq!(got, "hi THERE\n");
+        assert_eq!(ctx.captured.get("missing").map(String::as_str), Some(""));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_environment() {
+        let items = ItemParser {
+            env: &Environment::default(),
+        }
+        .parse(r#"B=2 sh -c "test $A$B = 12""#)
+        .expect("parsing");
+        let ctx = Context {
+            env: vec![("A".into(), "1".into())],
+            ..Context::default()
+        };
+        items[0]
+            .execute(&ctx, std::io::sink())
+            .expect("variables set");
+        assert!(items[0]
+            .execute(&Context::default(), std::io::sink())
+            .is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_directory_changes() {
+        let dir = TempDir::new("chdir");
+        std::fs::create_dir_all(dir.join("sub")).expect("creating dir");
+        let items = ItemParser {
+            env: &Environment::default(),
+        }
+        .parse("pwd > pwd.txt\ncp pwd.txt copy.txt")
+        .expect("parsing");
+
+        let mut ctx = Context::default();
+        ctx.chdir(&Chdir::Cd(dir.clone()), true).expect("cd");
+        ctx.chdir(&Chdir::Pushd("sub".into()), true).expect("pushd");
+        for item in &items {
+            item.execute(&ctx, std::io::sink()).expect("running in sub");
+        }
+        let pwd = std::fs::read_to_string(dir.join("sub/copy.txt")).expect("reading output");
+        assert!(pwd.trim_end().ends_with("sub"), "{}", pwd);
+
+        ctx.chdir(&Chdir::Popd, true).expect("popd");
+        assert_eq!(ctx.dir, Some(dir.clone()));
+        assert!(ctx.chdir(&Chdir::Popd, true).is_err());
+        assert!(ctx.chdir(&Chdir::Cd(dir.join("missing")), true).is_err());
+
+        // Failures point at the line that changed directory.
+        let items = ItemParser {
+            env: &Environment::default(),
+        }
+        .parse("\ncd missing")
+        .expect("parsing");
+        let err = ctx.run(items, &|err| err).expect_err("cd missing");
+        assert_eq!(err.span.map(|s| s.line), Some(2));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_parallel_fail_fast() {
+        let items = ItemParser {
+            env: &Environment::default(),
+        }
+        .parse("& sleep 5\n& - sh -c \"exit 1\"\n& sh -c \"exit 4\"")
+        .expect("parsing");
+        let start = std::time::Instant::now();
+        let err = items[0]
+            .execute(&Context::default(), std::io::sink())
+            .expect_err("failing job");
+        assert_eq!(err.code(), 4);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
 }
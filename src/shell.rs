@@ -0,0 +1,304 @@
+// Shell is an interactive prompt, "run --shell [file.run]", that runs each
+// line as it's entered. Variables, exports and the working directory carry
+// over from one line to the next.
+//
+// Lines starting with ":" control the shell itself:
+//
+//  :load file.run [task]  run a task of a run file, the default if not given
+//  :vars                  list variables
+//  :set name value        set a variable, "$(name)"
+//  :history               list previous lines
+//  :quit                  leave, as does end of input
+//
+// Every line is appended to a history file, so that it outlives the session.
+
+use crate::env::Environment;
+use crate::error::Error;
+use crate::load;
+use crate::parser::ItemParser;
+use crate::pipeline::Context;
+use crate::task::RunFile;
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+
+pub struct Shell {
+    pub env: Environment,
+    pub ctx: Context,
+    // Lines entered, oldest first, including those of earlier sessions.
+    pub history: Vec<String>,
+    // File the history is kept in, none if there's nowhere to keep it.
+    pub history_file: Option<PathBuf>,
+}
+
+impl Shell {
+    pub fn new(env: Environment, ctx: Context, history_file: Option<PathBuf>) -> Self {
+        let history = history_file
+            .as_ref()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .map(|text| text.lines().map(String::from).collect())
+            .unwrap_or_default();
+        Shell {
+            env,
+            ctx,
+            history,
+            history_file,
+        }
+    }
+
+    // Where history is kept by default: $RUN_HISTORY, or ".run_history" in the
+    // home directory.
+    pub fn default_history_file() -> Option<PathBuf> {
+        if let Some(path) = std::env::var_os("RUN_HISTORY") {
+            return Some(path.into());
+        }
+        std::env::var_os("HOME")
+            .or_else(|| std::env::var_os("USERPROFILE"))
+            .map(|home| PathBuf::from(home).join(".run_history"))
+    }
+
+    // Prompt for lines from `input` until it ends or the user quits.
+    // Failures are reported and the prompt carries on.
+    pub fn repl(&mut self, mut input: impl BufRead, mut prompt: impl Write) -> Result<(), Error> {
+        let mut line = String::new();
+        loop {
+            write!(prompt, "run> ")?;
+            prompt.flush()?;
+            line.clear();
+            if input
+                .read_line(&mut line)
+                .map_err(|e| Error::io("reading input", e))?
+                == 0
+            {
+                writeln!(prompt)?;
+                return Ok(());
+            }
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            self.remember(line);
+            let result = self.line(line, &mut prompt);
+            // Lines are parsed against the shell's variables, so what let
+            // captures becomes one of them.
+            self.env.named.extend(self.ctx.captured.drain());
+            match result {
+                Ok(true) => {}
+                Ok(false) => return Ok(()),
+                Err(err) => eprintln!("{}", err.report()),
+            }
+        }
+    }
+
+    // Run a single line, returning false if the shell should stop.
+    fn line(&mut self, line: &str, out: &mut dyn Write) -> Result<bool, Error> {
+        let (command, rest) = match line.strip_prefix(':') {
+            Some(command) => command.split_once(' ').unwrap_or((command, "")),
+            None => {
+                let items = ItemParser { env: &self.env }
+                    .parse(line)
+                    .map_err(|e| e.with_source("shell", line))?;
+                self.ctx
+                    .run(items, &|e: Error| e.with_source("shell", line))?;
+                return Ok(true);
+            }
+        };
+        let rest = rest.trim();
+        match command {
+            "load" => {
+                let mut args = rest.split_whitespace();
+                let path = args
+                    .next()
+                    .ok_or_else(|| Error::parse(":load expects a file"))?;
+                self.load(path, args.next())?;
+            }
+            "vars" => {
+                let mut named: Vec<_> = self.env.named.iter().collect();
+                named.sort();
+                for (name, value) in named {
+                    writeln!(out, "{} = {}", name, value)?;
+                }
+                for (index, value) in self.env.positional.iter().enumerate() {
+                    writeln!(out, "{} = {}", index + 1, value)?;
+                }
+                for (name, value) in &self.ctx.env {
+                    writeln!(out, "export {}={}", name, value)?;
+                }
+            }
+            "set" => {
+                let (name, value) = rest
+                    .split_once(' ')
+                    .map(|(name, value)| (name, value.trim()))
+                    .unwrap_or((rest, ""));
+                if name.is_empty() {
+                    return Err(Error::parse(":set expects a name and value"));
+                }
+                self.set(name, value)?;
+            }
+            "history" => {
+                for (number, line) in self.history.iter().enumerate() {
+                    writeln!(out, "{:>4}  {}", number + 1, line)?;
+                }
+            }
+            "quit" | "exit" | "q" => return Ok(false),
+            "help" => writeln!(
+                out,
+                ":load file.run [task], :vars, :set name value, :history, :quit"
+            )?,
+            _ => return Err(Error::parse(format!("unknown command: :{}", command))),
+        }
+        Ok(true)
+    }
+
+    // Run a task of the run file at `path`, relative to the current directory,
+    // with the shell's variables.
+    pub fn load(&mut self, path: &str, task: Option<&str>) -> Result<(), Error> {
+        let mut path = path.to_owned();
+        if !path.ends_with(".run") {
+            path.push_str(".run");
+        }
+        let path = self.ctx.path(&path).display().to_string();
+        let file =
+            fs::read_to_string(&path).map_err(|e| Error::io(format!("reading {}", path), e))?;
+        let located = |err: Error| err.with_source(&path, &file);
+
+        let run_file = RunFile::parse(&file).map_err(located)?;
+        let plan = run_file.plan(task).map_err(located)?;
+
+        // Only the variables the file declares are checked against it, since
+        // the shell's others are meant for other lines.
+        let declared = |name: &String| run_file.params.iter().any(|p| &p.name == name);
+        let positions = run_file.params.iter().filter_map(|p| p.position()).max();
+        let mut args = Environment {
+            named: self
+                .env
+                .named
+                .iter()
+                .filter(|(name, _)| declared(name))
+                .map(|(name, value)| (name.clone(), value.clone()))
+                .collect(),
+            positional: self.env.positional.clone(),
+        };
+        args.positional.truncate(positions.unwrap_or(0));
+        args.validate(&run_file.params)?;
+        // Defaults stick, as though they'd been set.
+        for (name, value) in args.named {
+            self.env.named.entry(name).or_insert(value);
+        }
+        if args.positional.len() > self.env.positional.len() {
+            let given = self.env.positional.len();
+            self.env.positional.extend(args.positional.drain(given..));
+        }
+
+        let mut stack = vec![load::identity(Path::new(&path))];
+        let items = load::items(&path, &file, &plan, &self.env, &mut stack)?;
+        self.ctx.run(items, &located)
+    }
+
+    // Set a named variable, or a positional one if `name` is a number.
+    fn set(&mut self, name: &str, value: &str) -> Result<(), Error> {
+        let value = value.to_owned();
+        let index = match name.parse::<usize>() {
+            Ok(index) => index,
+            Err(_) => {
+                self.env.named.insert(name.to_owned(), value);
+                return Ok(());
+            }
+        };
+        let positional = &mut self.env.positional;
+        match index {
+            0 => return Err(Error::parse("positional variables start at 1")),
+            _ if index <= positional.len() => positional[index - 1] = value,
+            _ if index == positional.len() + 1 => positional.push(value),
+            _ => {
+                return Err(Error::parse(format!(
+                    "set {} first, positional variables can't have gaps",
+                    positional.len() + 1
+                )))
+            }
+        }
+        Ok(())
+    }
+
+    // Add a line to the history, keeping it on disk as it goes.
+    // Failing to keep it isn't worth interrupting the session over.
+    fn remember(&mut self, line: &str) {
+        self.history.push(line.to_owned());
+        if let Some(path) = &self.history_file {
+            let appended = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| writeln!(file, "{}", line));
+            if let Err(err) = appended {
+                eprintln!("warning: saving history to {}: {}", path.display(), err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::testing::TempDir;
+    use pretty_assertions::assert_eq;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_repl() {
+        let dir = TempDir::new("shell");
+        fs::create_dir_all(dir.join("sub")).expect("creating dir");
+        fs::write(
+            dir.join("greet.run"),
+            "param who = world\necho $(who) > who.txt\n",
+        )
+        .expect("writing run file");
+        let history = dir.join("history");
+
+        let ctx = Context {
+            dir: Some(dir.clone()),
+            ..Context::default()
+        };
+        let mut shell = Shell::new(Environment::default(), ctx, Some(history.clone()));
+        let input = "\
+:set name there
+let greeting = echo hi
+cd sub
+echo $(greeting) $(name) > hi.txt
+:load ../greet
+:nope
+:vars
+";
+        let mut out = vec![];
+        shell.repl(input.as_bytes(), &mut out).expect("running");
+
+        assert_eq!(
+            fs::read_to_string(dir.join("sub/hi.txt")).expect("reading output"),
+            "hi there\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("sub/who.txt")).expect("reading output"),
+            "world\n"
+        );
+        let out = String::from_utf8(out).expect("utf8 output");
+        assert!(
+            out.ends_with("run> greeting = hi\nname = there\nwho = world\nrun> \n"),
+            "{}",
+            out
+        );
+
+        let shell = Shell::new(Environment::default(), Context::default(), Some(history));
+        assert_eq!(shell.history.len(), 7);
+        assert_eq!(shell.history[2], "cd sub");
+    }
+
+    #[test]
+    fn test_set() {
+        let mut shell = Shell::new(Environment::default(), Context::default(), None);
+        shell.set("1", "a").expect("first positional");
+        shell.set("1", "b").expect("replacing positional");
+        assert!(shell.set("3", "c").is_err());
+        assert!(shell.set("0", "c").is_err());
+        assert_eq!(shell.env.positional, vec!["b"]);
+    }
+}
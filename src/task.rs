@@ -0,0 +1,451 @@
+// A run file is a list of tasks.
+//
+// A task is declared by an unindented "name:" header followed by indented
+// lines:
+//
+//  build:
+//      cargo build
+//  test:
+//      cargo test
+//
+// A header can list prerequisite tasks, which run (once each) beforehand:
+//
+//  release: build test
+//      cargo publish
+//
+// Lines that appear before the first header form the implicit "default" task,
+// so flat run files keep working as they always have.
+//
+// Parameter declarations (see params.rs) may precede the first task.
+//
+// Splitting into tasks happens before ItemParser sees any lines, because only
+// the selected task should be parsed against the environment.
+
+use crate::error::{Error, Span};
+use crate::params::Param;
+
+pub const DEFAULT_TASK: &str = "default";
+
+#[derive(Debug, PartialEq)]
+pub struct Task {
+    pub name: String,
+    // Names of the tasks that must run before this one.
+    pub deps: Vec<String>,
+    // Verbatim lines belonging to the task, ready for ItemParser.
+    pub body: String,
+    // Line number (1-based) of the first line in body.
+    pub line: usize,
+    // The comment directly above the task's header.
+    pub description: Option<String>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct RunFile {
+    pub tasks: Vec<Task>,
+    pub params: Vec<Param>,
+    // The comment the file starts with.
+    pub description: Option<String>,
+}
+
+impl RunFile {
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        let mut tasks = vec![Task {
+            name: DEFAULT_TASK.into(),
+            deps: vec![],
+            body: String::new(),
+            line: 1,
+            description: None,
+        }];
+        let mut params: Vec<Param> = vec![];
+        // The comment on the previous line, if any, along with where it starts
+        // in the default task's body, in case it describes a parameter or
+        // task.
+        let mut comment: Option<(usize, &str)> = None;
+
+        for (index, line) in s.lines().enumerate() {
+            let number = index + 1;
+            let indented = line.starts_with(char::is_whitespace);
+            let trimmed = line.trim();
+            let described = comment.take();
+
+            if !indented {
+                if let Some(param) = Param::parse(line, number) {
+                    if tasks.len() > 1 {
+                        return Err(Error::parse("parameters must be declared before any task")
+                            .at(Span::of(number, line, trimmed)));
+                    }
+                    let mut param = param?;
+                    if params.iter().any(|p| p.name == param.name) {
+                        return Err(Error::parse(format!("duplicate parameter: {}", param.name))
+                            .at(Span::of(number, line, trimmed)));
+                    }
+                    // The description isn't part of the task, so it isn't
+                    // printed when the task runs.
+                    let body = &mut tasks[0].body;
+                    if let Some((start, text)) = described {
+                        body.truncate(start);
+                        body.push('\n');
+                        param.description = Some(uncomment(text));
+                    }
+                    body.push('\n');
+                    params.push(param);
+                    continue;
+                }
+                if trimmed.starts_with("//") {
+                    comment = Some((tasks[0].body.len(), trimmed));
+                }
+                if let Some((name, deps)) = header(trimmed) {
+                    if tasks.iter().any(|t| t.name == name) {
+                        return Err(Error::parse(format!("duplicate task: {}", name))
+                            .at(Span::of(number, line, name)));
+                    }
+                    tasks.push(Task {
+                        name: name.into(),
+                        deps,
+                        body: String::new(),
+                        line: number + 1,
+                        description: described.map(|(_, text)| uncomment(text)),
+                    });
+                    continue;
+                }
+            }
+
+            // Bodies are kept line-for-line with the file, so any line that
+            // doesn't belong to a task is kept as an empty line.
+            let in_default = tasks.len() == 1;
+            let task = tasks.last_mut().expect("default task");
+            if in_default || indented || trimmed.is_empty() {
+                task.body.push_str(line);
+            } else if !trimmed.starts_with("//") {
+                return Err(Error::parse(format!(
+                    "expected an indented line in task {}",
+                    task.name
+                ))
+                .at(Span::of(number, line, trimmed)));
+            }
+            task.body.push('\n');
+        }
+
+        // The implicit task only exists if it has something to run.
+        // A file of only tasks defaults to the first one, like make.
+        let has_default = tasks[0]
+            .body
+            .lines()
+            .map(|l| l.trim())
+            .any(|l| !l.is_empty() && !l.starts_with("//"));
+        if !has_default && tasks.len() > 1 {
+            tasks.remove(0);
+        }
+
+        for task in &tasks {
+            for dep in &task.deps {
+                if !tasks.iter().any(|t| &t.name == dep) {
+                    let err =
+                        Error::parse(format!("task {}: unknown dependency: {}", task.name, dep));
+                    // Point at the dependency in the task's header.
+                    let number = task.line - 1;
+                    let header = s.lines().nth(number - 1).unwrap_or_default();
+                    return Err(match header.split_whitespace().find(|w| w == dep) {
+                        Some(word) => err.at(Span::of(number, header, word)),
+                        None => err,
+                    });
+                }
+            }
+        }
+
+        let description = s
+            .lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty())
+            .filter(|line| line.starts_with("//"))
+            .map(uncomment);
+
+        Ok(RunFile {
+            tasks,
+            params,
+            description,
+        })
+    }
+
+    // Describe the file, named `name`, and its tasks for "--list":
+    //
+    //  build.run  Build and test the project.
+    //    build    Compile everything.
+    //    test
+    pub fn list(&self, name: &str) -> String {
+        let mut list = name.to_owned();
+        if let Some(description) = &self.description {
+            list.push_str(&format!("  {}", description));
+        }
+        let width = self.tasks.iter().map(|t| t.name.chars().count()).max();
+        for task in &self.tasks {
+            let line = match &task.description {
+                Some(description) => format!(
+                    "  {:width$}  {}",
+                    task.name,
+                    description,
+                    width = width.unwrap_or(0)
+                ),
+                None => format!("  {}", task.name),
+            };
+            list.push('\n');
+            list.push_str(&line);
+        }
+        list
+    }
+
+    // Lookup a task by name, or the default task if no name is given.
+    pub fn task(&self, name: Option<&str>) -> Option<&Task> {
+        match name {
+            Some(name) => self.tasks.iter().find(|t| t.name == name),
+            None => self.tasks.first(),
+        }
+    }
+
+    // Resolve the tasks to run for the named task (or the default), ordered
+    // such that every task comes after its dependencies.
+    // Each task appears exactly once, even if depended on many times.
+    pub fn plan(&self, name: Option<&str>) -> Result<Vec<&Task>, Error> {
+        let root = match self.task(name) {
+            Some(task) => task,
+            None => {
+                return Err(Error::parse(format!(
+                    "no such task: {}",
+                    name.unwrap_or(DEFAULT_TASK)
+                )))
+            }
+        };
+        let mut plan = vec![];
+        self.visit(root, &mut vec![], &mut plan)?;
+        Ok(plan)
+    }
+
+    // Depth-first traversal, pushing tasks in post-order.
+    // `path` holds the chain of tasks currently being visited, so seeing a
+    // task that is already on it means the graph has a cycle.
+    fn visit<'a>(
+        &'a self,
+        task: &'a Task,
+        path: &mut Vec<&'a str>,
+        plan: &mut Vec<&'a Task>,
+    ) -> Result<(), Error> {
+        if plan.iter().any(|t| t.name == task.name) {
+            return Ok(());
+        }
+        if let Some(start) = path.iter().position(|name| *name == task.name) {
+            let mut cycle = path[start..].to_vec();
+            cycle.push(&task.name);
+            return Err(Error::parse(format!(
+                "dependency cycle: {}",
+                cycle.join(" -> ")
+            )));
+        }
+        path.push(&task.name);
+        for dep in &task.deps {
+            let dep = self.task(Some(dep)).ok_or_else(|| {
+                Error::parse(format!("task {}: unknown dependency: {}", task.name, dep))
+            })?;
+            self.visit(dep, path, plan)?;
+        }
+        path.pop();
+        plan.push(task);
+        Ok(())
+    }
+}
+
+// The text of a "// comment".
+fn uncomment(line: &str) -> String {
+    line.trim_start_matches('/').trim().into()
+}
+
+// Parse a task header "name: deps...", returning the name and dependencies.
+fn header(line: &str) -> Option<(&str, Vec<String>)> {
+    let mut words = line.split_whitespace();
+    let name = words.next()?.strip_suffix(':')?;
+    let deps = words.map(String::from).collect::<Vec<_>>();
+    if is_ident(name) && deps.iter().all(|dep| is_ident(dep)) {
+        Some((name, deps))
+    } else {
+        None
+    }
+}
+
+fn is_ident(s: &str) -> bool {
+    !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_flat_file() {
+        let input = "cargo build\n  cargo test\n";
+        let got = RunFile::parse(input).expect("parsing");
+        assert_eq!(
+            got,
+            RunFile {
+                tasks: vec![Task {
+                    name: DEFAULT_TASK.into(),
+                    deps: vec![],
+                    body: "cargo build\n  cargo test\n".into(),
+                    line: 1,
+                    description: None,
+                }],
+                params: vec![],
+                description: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_named_tasks() {
+        let input = r#"// Build and test.
+build:
+    cargo build
+
+    cargo build --release
+// Run the tests.
+test:
+    cargo test
+"#;
+        let got = RunFile::parse(input).expect("parsing");
+        assert_eq!(
+            got,
+            RunFile {
+                tasks: vec![
+                    Task {
+                        name: "build".into(),
+                        deps: vec![],
+                        body: "    cargo build\n\n    cargo build --release\n\n".into(),
+                        line: 3,
+                        description: Some("Build and test.".into()),
+                    },
+                    Task {
+                        name: "test".into(),
+                        deps: vec![],
+                        body: "    cargo test\n".into(),
+                        line: 8,
+                        description: Some("Run the tests.".into()),
+                    },
+                ],
+                params: vec![],
+                description: Some("Build and test.".into()),
+            }
+        );
+        assert_eq!(got.task(None).map(|t| t.name.as_str()), Some("build"));
+        assert_eq!(got.task(Some("test")).map(|t| t.line), Some(8));
+        assert_eq!(got.task(Some("release")), None);
+    }
+
+    #[test]
+    fn test_list() {
+        let input = "// Project tasks.
+
+echo default
+// Compile everything.
+build:
+    cargo build
+check:
+    cargo check
+// Run the tests, after building.
+test: build
+    cargo test
+";
+        let got = RunFile::parse(input).expect("parsing").list("project.run");
+        let want = "project.run  Project tasks.
+  default
+  build    Compile everything.
+  check
+  test     Run the tests, after building.";
+        assert_eq!(got, want);
+    }
+
+    #[test]
+    fn test_default_with_tasks() {
+        let input = "echo default\nrelease:\n  echo release\n";
+        let got = RunFile::parse(input).expect("parsing");
+        let names: Vec<_> = got.tasks.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, vec![DEFAULT_TASK, "release"]);
+        assert_eq!(
+            got.task(None).map(|t| t.body.as_str()),
+            Some("echo default\n")
+        );
+    }
+
+    #[test]
+    fn test_task_errors() {
+        assert!(RunFile::parse("build:\n  a\nbuild:\n  b\n").is_err());
+        assert!(RunFile::parse("build:\n  a\nb\n").is_err());
+        let err =
+            RunFile::parse("\nbuild: test missing\n  a\ntest:\n  b\n").expect_err("dependency");
+        assert_eq!(
+            err.span,
+            Some(Span {
+                line: 2,
+                col: 13,
+                len: 7,
+            })
+        );
+    }
+
+    #[test]
+    fn test_params() {
+        let input = r#"// Builds things.
+// C compiler to use.
+param cc: string = gcc
+param 1: path
+build:
+    $(cc) $(1)
+"#;
+        let got = RunFile::parse(input).expect("parsing");
+        let described: Vec<_> = got
+            .params
+            .iter()
+            .map(|p| (p.name.as_str(), p.description.as_deref()))
+            .collect();
+        assert_eq!(
+            described,
+            vec![("cc", Some("C compiler to use.")), ("1", None)]
+        );
+        // Only the file's own comment is left in the default task.
+        assert_eq!(got.tasks[0].name, "build");
+
+        let err = RunFile::parse("build:\n  a\nparam cc\n").expect_err("late parameter");
+        assert_eq!(err.span.map(|s| s.line), Some(3));
+        assert!(RunFile::parse("param cc\nparam cc: int\n").is_err());
+    }
+
+    #[test]
+    fn test_plan() {
+        let input = r#"
+release: build test
+    cargo publish
+test: build
+    cargo test
+build:
+    cargo build
+lint:
+    cargo clippy
+"#;
+        let file = RunFile::parse(input).expect("parsing");
+        let plan = file.plan(Some("release")).expect("planning");
+        let names: Vec<_> = plan.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, vec!["build", "test", "release"]);
+        assert_eq!(file.tasks[0].deps, vec!["build", "test"]);
+        assert!(file.plan(Some("missing")).is_err());
+    }
+
+    #[test]
+    fn test_plan_cycle() {
+        let input = "a: b\n  one\nb: c\n  two\nc: a\n  three\n";
+        let file = RunFile::parse(input).expect("parsing");
+        assert_eq!(
+            file.plan(Some("a")).map_err(|e| e.to_string()).err(),
+            Some("dependency cycle: a -> b -> c -> a".to_owned())
+        );
+    }
+}
@@ -62,6 +62,47 @@ where
     }
 }
 
+// Helpers shared by the tests of every module.
+#[cfg(test)]
+pub(crate) mod testing {
+    use std::fs;
+    use std::ops::Deref;
+    use std::path::{Path, PathBuf};
+
+    // A directory of its own for a test, removed once the test is done with
+    // it, whether or not it passed.
+    pub struct TempDir(PathBuf);
+
+    impl TempDir {
+        pub fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("run-{}-{}", name, std::process::id()));
+            fs::remove_dir_all(&path).ok();
+            fs::create_dir_all(&path).expect("creating temp dir");
+            TempDir(path)
+        }
+    }
+
+    impl Deref for TempDir {
+        type Target = PathBuf;
+
+        fn deref(&self) -> &PathBuf {
+            &self.0
+        }
+    }
+
+    impl AsRef<Path> for TempDir {
+        fn as_ref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            fs::remove_dir_all(&self.0).ok();
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
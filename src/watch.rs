@@ -0,0 +1,229 @@
+// Watching, "run --watch", runs a run file's items and then runs them again
+// each time the files they depend on change: the run file and any it
+// includes, the inputs of incremental items, and whatever "--watch-glob"
+// patterns match.
+//
+// Files are polled rather than watched via each platform's notifications,
+// which keeps things portable at the cost of a short delay. A burst of
+// changes, like an editor saving several files, is waited out so that it
+// causes a single rerun, and a run still going when files change is cancelled.
+
+use crate::error::{Error, ErrorKind};
+use crate::parser::Item;
+use crate::pipeline::{Cancel, Context};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+// How often files are checked for changes.
+const POLL: Duration = Duration::from_millis(250);
+// How long files must go unchanged after a change before rerunning.
+const SETTLE: Duration = Duration::from_millis(100);
+
+// Modification times of the watched files.
+type Snapshot = BTreeMap<PathBuf, SystemTime>;
+
+// A run of the items in the background.
+type Running = (Cancel, JoinHandle<Result<(), Error>>);
+
+// Run the items `load` returns, the text of the run file at `path` along with
+// its items, and then load and run them again whenever files change, until
+// interrupted. The run file is reloaded each time so that edits to it apply.
+pub fn watch(
+    ctx: &Context,
+    path: &str,
+    globs: &[String],
+    load: impl Fn() -> Result<(String, Vec<Item>), Error>,
+) -> Result<(), Error> {
+    loop {
+        let mut patterns: Vec<String> = globs.iter().map(|glob| ctx.pattern(glob)).collect();
+        patterns.push(glob::Pattern::escape(path));
+        let loaded = load();
+        if let Ok((_, items)) = &loaded {
+            watched(&mut ctx.clone(), items, &mut patterns);
+        }
+        let before = snapshot(&patterns)?;
+        let mut running = match loaded {
+            Ok((text, items)) => Some(start(ctx, path, text, items)),
+            Err(err) => {
+                eprintln!("{}", err.report());
+                None
+            }
+        };
+
+        let mut idle = running.is_none();
+        let after = loop {
+            if idle {
+                println!("// watching for changes");
+                idle = false;
+            }
+            thread::sleep(POLL);
+            if let Some(run) = running.take_if(|(_, handle)| handle.is_finished()) {
+                finish(run);
+                idle = true;
+            }
+            let now = snapshot(&patterns)?;
+            if now != before {
+                break settle(&patterns, now)?;
+            }
+        };
+        if let Some(run) = running {
+            run.0.cancel();
+            finish(run);
+        }
+        println!(
+            "\n// ---- changed: {} ----",
+            changes(&before, &after).join(", ")
+        );
+    }
+}
+
+// Run items on another thread, so that they can be cancelled midway.
+fn start(ctx: &Context, path: &str, text: String, items: Vec<Item>) -> Running {
+    let mut ctx = Context {
+        cancel: ctx.cancel.child(),
+        ..ctx.clone()
+    };
+    let cancel = ctx.cancel.clone();
+    let path = path.to_owned();
+    let handle = thread::spawn(move || ctx.run(items, &|err| err.with_source(&path, &text)));
+    (cancel, handle)
+}
+
+// Wait for a run to finish, reporting how it failed unless it was cancelled.
+fn finish((_, handle): Running) {
+    match handle.join() {
+        Ok(Err(err)) if !matches!(err.kind, ErrorKind::Cancelled) => {
+            eprintln!("{}", err.report())
+        }
+        _ => {}
+    }
+}
+
+// Add patterns for the files `items` depend on: the run files they include
+// and the inputs of incremental items, resolved against the directory they're
+// declared in.
+fn watched(ctx: &mut Context, items: &[Item], patterns: &mut Vec<String>) {
+    for item in items {
+        match item {
+            // A stack that's empty is reported when the items run.
+            Item::Chdir(change, _) => {
+                let _ = ctx.chdir(change, false);
+            }
+            Item::Include(include) => {
+                patterns.push(glob::Pattern::escape(&include.file));
+                watched(ctx, &include.items, patterns);
+            }
+            Item::Incremental { inputs, items, .. } => {
+                patterns.extend(inputs.iter().map(|input| ctx.pattern(input)));
+                watched(ctx, items, patterns);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn snapshot(patterns: &[String]) -> Result<Snapshot, Error> {
+    let mut snapshot = Snapshot::new();
+    for pattern in patterns {
+        let paths = glob::glob(pattern)
+            .map_err(|e| Error::parse(format!("invalid watch pattern {}: {}", pattern, e)))?;
+        for path in paths.filter_map(Result::ok) {
+            if let Ok(modified) = fs::metadata(&path).and_then(|m| m.modified()) {
+                snapshot.insert(path, modified);
+            }
+        }
+    }
+    Ok(snapshot)
+}
+
+// Wait for files to stop changing, returning them as they are then.
+fn settle(patterns: &[String], mut latest: Snapshot) -> Result<Snapshot, Error> {
+    loop {
+        thread::sleep(SETTLE);
+        let now = snapshot(patterns)?;
+        if now == latest {
+            return Ok(now);
+        }
+        latest = now;
+    }
+}
+
+// Files added, modified or removed between snapshots.
+fn changes(before: &Snapshot, after: &Snapshot) -> Vec<String> {
+    let mut paths: Vec<&PathBuf> = after
+        .iter()
+        .filter(|(path, modified)| before.get(*path) != Some(modified))
+        .map(|(path, _)| path)
+        .chain(before.keys().filter(|path| !after.contains_key(*path)))
+        .collect();
+    paths.sort();
+    paths
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::ItemParser;
+    use crate::util::testing::TempDir;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_watched() {
+        let items = ItemParser {
+            env: &Default::default(),
+        }
+        .parse("inputs *.c\ncc main.c\ncd sub\ninputs *.h\noutputs x\ncc x.c")
+        .expect("parsing");
+        let mut patterns = vec![];
+        watched(&mut Context::default(), &items, &mut patterns);
+        assert_eq!(
+            patterns,
+            vec![
+                "*.c".to_owned(),
+                format!("sub{}*.h", std::path::MAIN_SEPARATOR)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_changes() {
+        let dir = TempDir::new("watch");
+        let patterns = vec![format!("{}/*.txt", dir.display())];
+        let write = |name: &str, age: u64| {
+            let file = fs::File::create(dir.join(name)).expect("creating file");
+            file.set_modified(SystemTime::now() - Duration::from_secs(age))
+                .expect("setting modified time");
+        };
+        let names = |before: &Snapshot, after: &Snapshot| -> Vec<String> {
+            changes(before, after)
+                .iter()
+                .map(|path| {
+                    path.rsplit(['/', '\\'])
+                        .next()
+                        .unwrap_or_default()
+                        .to_owned()
+                })
+                .collect()
+        };
+
+        write("a.txt", 10);
+        write("b.txt", 10);
+        write("c.log", 10);
+        let before = snapshot(&patterns).expect("snapshot");
+        assert_eq!(before.len(), 2);
+        assert_eq!(snapshot(&patterns).expect("snapshot"), before);
+
+        write("b.txt", 0);
+        write("c.log", 0);
+        fs::remove_file(dir.join("a.txt")).expect("removing file");
+        write("d.txt", 0);
+        let after = snapshot(&patterns).expect("snapshot");
+        assert_eq!(names(&before, &after), vec!["a.txt", "b.txt", "d.txt"]);
+    }
+}